@@ -0,0 +1,72 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+fn main() {
+    readings();
+    jlpt_levels();
+}
+
+// compile data/readings.tsv into a phf map of common words to their readings
+fn readings() {
+    println!("cargo:rerun-if-changed=data/readings.tsv");
+    let tsv = fs::read_to_string("data/readings.tsv").unwrap();
+    let mut map = phf_codegen::Map::new();
+    for line in tsv.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let word = fields.next().unwrap();
+        let reading = fields
+            .next()
+            .unwrap_or_else(|| panic!("no reading for {}", word));
+        map.entry(word, &format!("{:?}", reading));
+    }
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("readings.rs");
+    let mut out = BufWriter::new(File::create(&path).unwrap());
+    writeln!(
+        &mut out,
+        "static LOCAL_READINGS: phf::Map<&'static str, &'static str> = {};",
+        map.build()
+    )
+    .unwrap();
+}
+
+// compile data/jlpt.tsv into a phf map of kanji to their JLPT level
+fn jlpt_levels() {
+    println!("cargo:rerun-if-changed=data/jlpt.tsv");
+    let tsv = fs::read_to_string("data/jlpt.tsv").unwrap();
+    let mut kanji = Vec::new();
+    for line in tsv.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let level = fields.next().unwrap();
+        let level = level
+            .parse::<u8>()
+            .unwrap_or_else(|_| panic!("invalid JLPT level {}", level));
+        let chars = fields
+            .next()
+            .unwrap_or_else(|| panic!("no kanji for level {}", level));
+        for ch in chars.chars() {
+            kanji.push((ch.to_string(), level));
+        }
+    }
+    let mut map = phf_codegen::Map::new();
+    for (ch, level) in &kanji {
+        map.entry(ch.as_str(), &level.to_string());
+    }
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("jlpt.rs");
+    let mut out = BufWriter::new(File::create(&path).unwrap());
+    writeln!(
+        &mut out,
+        "static JLPT_LEVELS: phf::Map<&'static str, u8> = {};",
+        map.build()
+    )
+    .unwrap();
+}
@@ -0,0 +1,43 @@
+#![no_main]
+use add_ruby::html::{analyze_jp, decode_entities};
+use add_ruby::ruby::{generate_html_with_ruby, ruby_template, KanjiRuby};
+use libfuzzer_sys::fuzz_target;
+
+// Whatever the input, the parts cover it end to end in order, so the page can be
+// put back together from them, and every run is the decoded text of a part that
+// needs ruby. Slicing at an offset inside a char panics, as does any scanner bug.
+// Without readings, generating the page gives back the input byte for byte.
+fuzz_target!(|html: &str| {
+    let skip_tags = vec!["kbd".to_string()];
+    for &code_ruby in &[false, true] {
+        let (html_parts, jp_runs) = analyze_jp(html, code_ruby, &skip_tags);
+        let mut end = 0;
+        let mut runs = jp_runs.iter();
+        for part in &html_parts {
+            assert_eq!(
+                part.start, end,
+                "gap or overlap before part {}..{}",
+                part.start, part.end
+            );
+            let content = &html[part.start..part.end];
+            if part.need_ruby {
+                assert_eq!(runs.next(), Some(&decode_entities(content)));
+            }
+            end = part.end;
+        }
+        assert_eq!(end, html.len(), "parts end before the input");
+        assert!(runs.next().is_none(), "more runs than parts that need ruby");
+
+        let page = generate_html_with_ruby(
+            html,
+            &html_parts,
+            &jp_runs,
+            &ruby_template("(", ")", true),
+            KanjiRuby::Spaced,
+            false,
+            |jp_runs: &[String]| Ok(vec![None; jp_runs.len()]),
+        )
+        .unwrap();
+        assert_eq!(page, html, "the page changed without readings");
+    }
+});
@@ -0,0 +1,122 @@
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+// content codings this service can decode and encode again, in preference order
+const SUPPORTED_ENCODINGS: [&str; 2] = ["br", "gzip"];
+
+// The codings from an Accept-Encoding header that we can handle, most preferred first.
+pub(crate) fn accepted_encodings(accept_encoding: &str) -> Vec<&'static str> {
+    let mut accepted: Vec<(&'static str, f32)> = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let supported = SUPPORTED_ENCODINGS
+            .iter()
+            .find(|supported| **supported == coding);
+        if let Some(supported) = supported {
+            if quality > 0.0 {
+                accepted.push((supported, quality));
+            }
+        }
+    }
+    accepted.sort_by(|a, b| {
+        b.1.partial_cmp(&a.1)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| {
+                let rank = |coding| SUPPORTED_ENCODINGS.iter().position(|s| *s == coding);
+                rank(a.0).cmp(&rank(b.0))
+            })
+    });
+    accepted.into_iter().map(|(coding, _)| coding).collect()
+}
+
+// Decodes at most max_bytes + 1 bytes, a result longer than max_bytes means the page
+// is too large; a small compressed body can inflate to far more than it says.
+pub(crate) fn decompress(
+    content_encoding: Option<&str>,
+    body: &[u8],
+    max_bytes: usize,
+) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    decoder(content_encoding, body)?
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+// decodes a body as it is read, for streamed pages
+pub(crate) fn decoder<'a, R: Read + 'a>(
+    content_encoding: Option<&str>,
+    reader: R,
+) -> Result<Box<dyn Read + 'a>> {
+    match content_encoding {
+        None | Some("identity") => Ok(Box::new(reader)),
+        Some("gzip") | Some("x-gzip") => Ok(Box::new(GzDecoder::new(reader))),
+        Some("br") => Ok(Box::new(brotli::Decompressor::new(reader, 4096))),
+        Some(other) => Err(anyhow::anyhow!("unsupported content encoding: {}", other)),
+    }
+}
+
+pub(crate) fn compress(content_encoding: &str, body: &[u8]) -> Result<Vec<u8>> {
+    match content_encoding {
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body)?;
+            Ok(encoder.finish()?)
+        }
+        "br" => {
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+            encoder.write_all(body)?;
+            Ok(encoder.into_inner())
+        }
+        other => Err(anyhow::anyhow!("unsupported content encoding: {}", other)),
+    }
+}
+
+// compresses what is written to it as it goes, for streamed pages; dropping it
+// finishes the coding
+pub(crate) fn encoder<W: Write + 'static>(
+    content_encoding: Option<&str>,
+    writer: W,
+) -> Result<Box<dyn Write>> {
+    match content_encoding {
+        None => Ok(Box::new(writer)),
+        Some("gzip") => Ok(Box::new(GzEncoder::new(writer, Compression::default()))),
+        Some("br") => Ok(Box::new(brotli::CompressorWriter::new(writer, 4096, 5, 22))),
+        Some(other) => Err(anyhow::anyhow!("unsupported content encoding: {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let body = "<p>日本語の本</p>".repeat(100);
+        for coding in SUPPORTED_ENCODINGS.iter() {
+            let encoded = compress(coding, body.as_bytes()).unwrap();
+            let decoded = decompress(Some(coding), &encoded, body.len()).unwrap();
+            assert_eq!(decoded, body.as_bytes());
+        }
+    }
+
+    #[test]
+    fn stops_decoding_past_max_bytes() {
+        let body = vec![b' '; 10_000_000];
+        for coding in SUPPORTED_ENCODINGS.iter() {
+            let encoded = compress(coding, &body).unwrap();
+            assert!(encoded.len() < 100_000);
+            let decoded = decompress(Some(coding), &encoded, 1000).unwrap();
+            assert_eq!(decoded.len(), 1001);
+        }
+        assert_eq!(decompress(None, &body, 1000).unwrap().len(), 1001);
+    }
+}
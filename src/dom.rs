@@ -0,0 +1,273 @@
+use crate::html::{is_japanese, HtmlPart, VOID_TAGS};
+use html5ever::tendril::TendrilSink;
+use html5ever::{parse_document, ParseOpts};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+
+// their text is not parsed as markup, so it is written back unescaped; noscript is
+// among them as html5ever parses with scripting on
+const RAW_TEXT_TAGS: [&str; 8] = [
+    "iframe",
+    "noembed",
+    "noframes",
+    "noscript",
+    "plaintext",
+    "script",
+    "style",
+    "xmp",
+];
+
+// the parts are byte ranges of html, the serialized document
+struct DomWalker {
+    html: String,
+    html_parts: Vec<HtmlPart>,
+    jp_runs: Vec<String>,
+    part_start: usize,
+    in_title: bool,
+    code_ruby: bool,
+    skip_tags: Vec<String>,
+}
+
+pub fn analyze_jp_dom(
+    body_string: &str,
+    code_ruby: bool,
+    skip_tags: &[String],
+) -> (String, Vec<HtmlPart>, Vec<String>) {
+    let dom = parse_document(RcDom::default(), ParseOpts::default()).one(body_string);
+    let mut walker = DomWalker {
+        html: String::with_capacity(body_string.len()),
+        html_parts: Vec::new(),
+        jp_runs: Vec::new(),
+        part_start: 0,
+        in_title: false,
+        code_ruby,
+        skip_tags: skip_tags.to_vec(),
+    };
+    walker.walk(&dom.document, true, false);
+    walker.flush();
+    (walker.html, walker.html_parts, walker.jp_runs)
+}
+
+impl DomWalker {
+    fn walk(&mut self, handle: &Handle, annotate: bool, raw_text: bool) {
+        match handle.data {
+            NodeData::Document => self.walk_children(handle, annotate, raw_text),
+            // the public and system ids decide between standards and quirks mode
+            NodeData::Doctype {
+                ref name,
+                ref public_id,
+                ref system_id,
+            } => {
+                self.html.push_str("<!DOCTYPE ");
+                self.html.push_str(name);
+                if !public_id.is_empty() {
+                    self.html.push_str(&format!(" PUBLIC \"{}\"", public_id));
+                    if !system_id.is_empty() {
+                        self.html.push_str(&format!(" \"{}\"", system_id));
+                    }
+                } else if !system_id.is_empty() {
+                    self.html.push_str(&format!(" SYSTEM \"{}\"", system_id));
+                }
+                self.html.push('>');
+            }
+            NodeData::Comment { ref contents } => {
+                self.html.push_str(&format!("<!--{}-->", contents));
+            }
+            NodeData::ProcessingInstruction {
+                ref target,
+                ref contents,
+            } => {
+                self.html.push_str(&format!("<?{} {}>", target, contents));
+            }
+            NodeData::Text { ref contents } => {
+                let text = contents.borrow();
+                if raw_text {
+                    self.html.push_str(&text);
+                } else if annotate {
+                    self.push_text(&text);
+                } else {
+                    self.html.push_str(&escape_text(&text));
+                }
+            }
+            NodeData::Element {
+                ref name,
+                ref attrs,
+                ref template_contents,
+                ..
+            } => {
+                // SVG and MathML names keep their case, e.g. linearGradient
+                let tag = name.local.to_lowercase();
+                self.html.push('<');
+                self.html.push_str(&name.local);
+                for attr in attrs.borrow().iter() {
+                    self.html.push(' ');
+                    // e.g. xlink:href and xmlns:xlink on SVG elements
+                    if let Some(ref prefix) = attr.name.prefix {
+                        self.html.push_str(prefix);
+                        self.html.push(':');
+                    }
+                    self.html.push_str(&format!(
+                        "{}=\"{}\"",
+                        attr.name.local,
+                        escape_attr(&attr.value)
+                    ));
+                }
+                self.html.push('>');
+                if VOID_TAGS.contains(&tag.as_str()) {
+                    return;
+                }
+                let raw_text = RAW_TEXT_TAGS.contains(&tag.as_str());
+                let annotate = annotate
+                    && !raw_text
+                    && !["textarea", "ruby", "rb", "rt"].contains(&tag.as_str())
+                    && (self.code_ruby || (tag != "pre" && tag != "code"))
+                    && !self.skip_tags.contains(&tag);
+                let in_title = self.in_title;
+                self.in_title = in_title || tag == "title";
+                // the children of a <template> are kept apart from the element
+                match template_contents {
+                    Some(contents) => self.walk_children(contents, annotate, raw_text),
+                    None => self.walk_children(handle, annotate, raw_text),
+                }
+                self.in_title = in_title;
+                self.html.push_str(&format!("</{}>", name.local));
+            }
+        }
+    }
+
+    fn walk_children(&mut self, handle: &Handle, annotate: bool, raw_text: bool) {
+        for child in handle.children.borrow().iter() {
+            self.walk(child, annotate, raw_text);
+        }
+    }
+
+    // split a text node into Japanese runs and the text between them
+    fn push_text(&mut self, text: &str) {
+        let mut run = "".to_string();
+        for ch in text.chars() {
+            if is_japanese(&ch) {
+                run.push(ch);
+                continue;
+            }
+            if !run.is_empty() {
+                self.push_run(&run);
+                run.clear();
+            }
+            self.html.push_str(&escape_text(&ch.to_string()));
+        }
+        if !run.is_empty() {
+            self.push_run(&run);
+        }
+    }
+
+    fn push_run(&mut self, run: &str) {
+        self.flush();
+        self.jp_runs.push(run.to_string());
+        self.html.push_str(run);
+        let html_part = HtmlPart {
+            start: self.part_start,
+            end: self.html.len(),
+            need_ruby: true,
+            in_title: self.in_title,
+        };
+        self.html_parts.push(html_part);
+        self.part_start = self.html.len();
+    }
+
+    fn flush(&mut self) {
+        if self.part_start < self.html.len() {
+            let html_part = HtmlPart {
+                start: self.part_start,
+                end: self.html.len(),
+                need_ruby: false,
+                in_title: false,
+            };
+            self.html_parts.push(html_part);
+            self.part_start = self.html.len();
+        }
+    }
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('\u{a0}', "&nbsp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('\u{a0}', "&nbsp;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html::analyze_jp;
+
+    // Documents already in the form html5ever serializes, so both parsers read the same
+    // bytes; the markup between runs may be split into parts differently.
+    const CORPUS: [&str; 6] = [
+        "<html><head><title>日本語のページ</title></head><body><p>東京へ行く</p></body></html>",
+        "<!DOCTYPE html><html><head></head><body><p>Hello 世界!</p><p>三<b>月</b>です</p></body></html>",
+        "<html><head><style>p { color: red; }</style><script>var s = \"日本\";</script></head><body><p title=\"日本\">日本</p><!-- 漢字 --></body></html>",
+        "<html><head></head><body><pre>東京</pre><code>大阪</code><ruby>漢字<rt>かんじ</rt></ruby><textarea>京都</textarea></body></html>",
+        "<html><head></head><body><p>A &amp; B、漢字</p><template><p>名前</p></template></body></html>",
+        "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\"><html><head></head><body><p>漢字</p></body></html>",
+    ];
+
+    fn runs_of(html: &str, html_parts: &[HtmlPart]) -> Vec<(usize, usize, bool, String)> {
+        html_parts
+            .iter()
+            .filter(|part| part.need_ruby)
+            .map(|part| {
+                let text = html[part.start..part.end].to_string();
+                (part.start, part.end, part.in_title, text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn matches_analyze_jp_on_corpus() {
+        for doc in CORPUS.iter() {
+            let (html, dom_parts, dom_runs) = analyze_jp_dom(doc, false, &[]);
+            let (parts, runs) = analyze_jp(doc, false, &[]);
+            assert_eq!(html, *doc);
+            assert_eq!(dom_runs, runs, "{}", doc);
+            assert_eq!(runs_of(&html, &dom_parts), runs_of(doc, &parts), "{}", doc);
+            assert_eq!(dom_parts.last().map(|part| part.end), Some(html.len()));
+        }
+    }
+
+    #[test]
+    fn keeps_template_contents() {
+        let doc = "<html><head></head><body><template><p>名前</p></template></body></html>";
+        let (html, _, runs) = analyze_jp_dom(doc, false, &[]);
+        assert_eq!(html, doc);
+        assert_eq!(runs, vec!["名前"]);
+    }
+
+    #[test]
+    fn keeps_raw_text_unescaped() {
+        let doc = "<html><head><noscript><p>日本 &amp; 東京</p></noscript></head><body><xmp><b>大阪</b></xmp><iframe><p>京都</p></iframe><noembed><i>奈良</i></noembed><noframes><b>神戸</b></noframes></body></html>";
+        let (html, _, runs) = analyze_jp_dom(doc, false, &[]);
+        assert_eq!(html, doc);
+        assert!(runs.is_empty());
+    }
+
+    #[test]
+    fn keeps_doctype_ids() {
+        let doc =
+            "<!DOCTYPE html SYSTEM \"about:legacy-compat\"><html><head></head><body></body></html>";
+        assert_eq!(analyze_jp_dom(doc, false, &[]).0, doc);
+    }
+
+    #[test]
+    fn keeps_attribute_prefixes() {
+        let doc = "<html><head></head><body><svg xmlns:xlink=\"http://www.w3.org/1999/xlink\"><linearGradient></linearGradient><a xlink:href=\"#top\"><text>上へ</text></a></svg></body></html>";
+        let (html, _, runs) = analyze_jp_dom(doc, false, &[]);
+        assert_eq!(html, doc);
+        assert_eq!(runs, vec!["上へ"]);
+    }
+}
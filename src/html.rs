@@ -0,0 +1,985 @@
+use kanji::{is_hiragana, is_katakana};
+
+// elements without an end tag, they never hold text
+pub(crate) const VOID_TAGS: [&str; 18] = [
+    "area", "base", "basefont", "bgsound", "br", "col", "embed", "frame", "hr", "img", "input",
+    "keygen", "link", "meta", "param", "source", "track", "wbr",
+];
+
+// a byte range of the analyzed page
+pub struct HtmlPart {
+    pub start: usize,
+    pub end: usize,
+    pub need_ruby: bool,
+    // <title> can not hold markup, its reading is written inline
+    pub in_title: bool,
+}
+
+pub fn analyze_jp(
+    body_string: &str,
+    code_ruby: bool,
+    skip_tags: &[String],
+) -> (Vec<HtmlPart>, Vec<String>) {
+    let (html_parts, jp_runs, _) = scan(body_string, code_ruby, skip_tags, &mut Vec::new(), true);
+    (html_parts, jp_runs)
+}
+
+// Finds the runs of a page that arrives in pieces. feed takes the next piece and hands
+// back the text that the pieces after it can not change, with its parts and runs; only
+// the unfinished tail, e.g. an open tag or a run cut by the end of the piece, is kept.
+pub struct StreamAnalyzer {
+    code_ruby: bool,
+    skip_tags: Vec<String>,
+    tag_stack: Vec<String>,
+    pending: String,
+    // the tail kept by the last scan, it is scanned again once the pending text is twice
+    // as long; a long script or comment is not scanned again for each of its pieces
+    kept: usize,
+}
+
+impl StreamAnalyzer {
+    pub fn new(code_ruby: bool, skip_tags: &[String]) -> Self {
+        StreamAnalyzer {
+            code_ruby,
+            skip_tags: skip_tags.to_vec(),
+            tag_stack: Vec::new(),
+            pending: String::new(),
+            kept: 0,
+        }
+    }
+
+    // the parts are ranges of the text handed back; last is set with the final piece
+    pub fn feed(&mut self, piece: &str, last: bool) -> (String, Vec<HtmlPart>, Vec<String>) {
+        self.pending.push_str(piece);
+        if !last && self.pending.len() < self.kept * 2 {
+            return (String::new(), Vec::new(), Vec::new());
+        }
+        let (html_parts, jp_runs, end) = scan(
+            &self.pending,
+            self.code_ruby,
+            &self.skip_tags,
+            &mut self.tag_stack,
+            last,
+        );
+        let rest = self.pending.split_off(end);
+        self.kept = rest.len();
+        (
+            std::mem::replace(&mut self.pending, rest),
+            html_parts,
+            jp_runs,
+        )
+    }
+}
+
+// Scans html, the whole page when complete is set, and returns how much of it the parts
+// cover. Text that goes on after html is only complete up to the last tag start, where
+// the scan can be picked up again with tag_stack.
+fn scan(
+    body_string: &str,
+    code_ruby: bool,
+    skip_tags: &[String],
+    tag_stack: &mut Vec<String>,
+    complete: bool,
+) -> (Vec<HtmlPart>, Vec<String>, usize) {
+    let chars_num = body_string.chars().count();
+    let html_chars = body_string.chars().collect::<Vec<char>>();
+    let mut offsets = ByteOffsets {
+        html_chars: &html_chars,
+        char_pos: 0,
+        byte_pos: 0,
+    };
+    let mut i = 0;
+    let mut html_parts = Vec::new();
+    // the part being scanned is always html_chars[content_start..i]
+    let mut content_start = 0;
+    let mut jp_runs = Vec::new();
+    let mut tag_start = 0;
+    // a document or fragment may start with text instead of a tag
+    let mut in_text = chars_num > 0 && html_chars[0] != '<';
+    // the last place the scan can be picked up again: the parts and runs before it, its
+    // char position and the tags open there
+    let mut resume = (0, 0, 0, tag_stack.clone());
+    let mut in_tag = false;
+    let mut quote: Option<char> = None;
+    while i < chars_num {
+        let ch = html_chars[i];
+        count_steps(1);
+        if ch == '<' && quote.is_none() && starts_with_ignore_case(&html_chars[i..], "<!--") {
+            // comments may contain '>' and may run to the end of the document
+            if content_start < i {
+                let html_part = HtmlPart {
+                    start: offsets.at(content_start),
+                    end: offsets.at(i),
+                    need_ruby: false,
+                    in_title: false,
+                };
+                html_parts.push(html_part);
+            }
+            let comment_end = find_ignore_case(&html_chars, i + 4, "-->").map(|pos| pos + 3);
+            let end = comment_end.unwrap_or(chars_num);
+            let html_part = HtmlPart {
+                start: offsets.at(i),
+                end: offsets.at(end),
+                need_ruby: false,
+                in_title: false,
+            };
+            html_parts.push(html_part);
+            i = end;
+            content_start = i;
+            if comment_end.is_some() {
+                resume = (html_parts.len(), jp_runs.len(), i, tag_stack.clone());
+            }
+            in_text = i < chars_num && html_chars[i] != '<';
+            continue;
+        }
+        if (ch != '>' || quote.is_some()) && !in_text {
+            // a '>' inside a quoted attribute value does not close the tag
+            if quote == Some(ch) {
+                quote = None;
+            } else if quote.is_none() && in_tag && (ch == '"' || ch == '\'') {
+                quote = Some(ch);
+            } else if quote.is_none() && ch == '<' {
+                tag_start = i;
+                in_tag = true;
+            }
+            // skip to the next character that can change the scanner state
+            let next = html_chars[i + 1..]
+                .iter()
+                .position(|c| matches!(c, '<' | '>' | '"' | '\''))
+                .map(|pos| i + 1 + pos)
+                .unwrap_or(chars_num);
+            count_steps(next - i);
+            i = next;
+            continue;
+        }
+        in_text = false;
+        in_tag = false;
+        if ch == '>' {
+            // script and style bodies are not text and a textarea holds the value of a form
+            // field, copy them through untouched
+            count_steps(i - tag_start);
+            let last_tag = tag_name(&html_chars[tag_start..i]);
+            update_tag_stack(tag_stack, &last_tag, &html_chars[tag_start..i]);
+            if last_tag == "script" || last_tag == "style" || last_tag == "textarea" {
+                let body_end = find_ignore_case(&html_chars, i + 1, &format!("</{}", last_tag));
+                let end = body_end.unwrap_or(chars_num);
+                let html_part = HtmlPart {
+                    start: offsets.at(content_start),
+                    end: offsets.at(end),
+                    need_ruby: false,
+                    in_title: false,
+                };
+                html_parts.push(html_part);
+                i = end;
+                content_start = i;
+                if body_end.is_some() {
+                    resume = (html_parts.len(), jp_runs.len(), i, tag_stack.clone());
+                }
+                continue;
+            }
+        }
+        // text that is already annotated must not be wrapped a second time
+        let in_ruby = tag_stack
+            .iter()
+            .any(|tag| tag == "ruby" || tag == "rb" || tag == "rt");
+        let in_title = tag_stack.iter().any(|tag| tag == "title");
+        let in_code = !code_ruby && tag_stack.iter().any(|tag| tag == "pre" || tag == "code");
+        let in_skipped = tag_stack.iter().any(|tag| skip_tags.contains(tag));
+        loop {
+            count_steps(1);
+            // character references count as the character they decode to
+            let (ch, ch_len) = char_at(&html_chars, i);
+            i += ch_len;
+            // the end of the document ends a run like a tag does
+            let at_tag = i >= chars_num || html_chars[i] == '<';
+            // text before a tag that is not Japanese stays with the markup
+            let run_ends = if at_tag {
+                is_japanese(&ch)
+            } else {
+                is_japanese(&ch) != is_japanese(&char_at(&html_chars, i).0)
+            };
+            if run_ends {
+                let need_ruby = is_japanese(&ch) && !in_ruby && !in_code && !in_skipped;
+                let html_part = HtmlPart {
+                    start: offsets.at(content_start),
+                    end: offsets.at(i),
+                    need_ruby,
+                    in_title: need_ruby && in_title,
+                };
+                if need_ruby {
+                    jp_runs.push(decode_entities(
+                        &body_string[html_part.start..html_part.end],
+                    ));
+                }
+                html_parts.push(html_part);
+                content_start = i;
+            }
+            if at_tag {
+                if i < chars_num {
+                    resume = (html_parts.len(), jp_runs.len(), i, tag_stack.clone());
+                }
+                break;
+            }
+        }
+    }
+    if !complete {
+        let (parts, runs, pos, open_tags) = resume;
+        html_parts.truncate(parts);
+        jp_runs.truncate(runs);
+        *tag_stack = open_tags;
+        let start = html_parts.last().map_or(0, |part| part.end);
+        let end = html_chars[..pos].iter().map(|ch| ch.len_utf8()).sum();
+        if start < end {
+            html_parts.push(HtmlPart {
+                start,
+                end,
+                need_ruby: false,
+                in_title: false,
+            });
+        }
+        return (html_parts, jp_runs, end);
+    }
+    // markup left open at the end of the document
+    if content_start < chars_num {
+        let html_part = HtmlPart {
+            start: offsets.at(content_start),
+            end: offsets.at(chars_num),
+            need_ruby: false,
+            in_title: false,
+        };
+        html_parts.push(html_part);
+    }
+    (html_parts, jp_runs, body_string.len())
+}
+
+// HtmlPart ranges are in bytes while the scanner counts chars; parts are
+// found in order, so the byte position only ever moves forward
+struct ByteOffsets<'a> {
+    html_chars: &'a [char],
+    char_pos: usize,
+    byte_pos: usize,
+}
+
+impl<'a> ByteOffsets<'a> {
+    fn at(&mut self, char_pos: usize) -> usize {
+        count_steps(char_pos - self.char_pos);
+        self.byte_pos += self.html_chars[self.char_pos..char_pos]
+            .iter()
+            .map(|ch| ch.len_utf8())
+            .sum::<usize>();
+        self.char_pos = char_pos;
+        self.byte_pos
+    }
+}
+
+// The share of Japanese among the characters of the page's text, leaving out markup,
+// comments, script and style bodies and whitespace. A page without text counts as 0.
+pub fn japanese_ratio(html: &str) -> f64 {
+    let lower = html.to_ascii_lowercase();
+    let mut japanese = 0;
+    let mut text = 0;
+    let mut pos = 0;
+    while pos < html.len() {
+        let next_tag = html[pos..]
+            .find('<')
+            .map_or(html.len(), |found| pos + found);
+        for ch in html[pos..next_tag].chars().filter(|ch| !ch.is_whitespace()) {
+            text += 1;
+            if is_japanese(&ch) {
+                japanese += 1;
+            }
+        }
+        if next_tag == html.len() {
+            break;
+        }
+        let end = if lower[next_tag..].starts_with("<!--") {
+            lower[next_tag..].find("-->").map(|end| next_tag + end + 3)
+        } else if lower[next_tag..].starts_with("<script")
+            || lower[next_tag..].starts_with("<style")
+        {
+            let name = if lower[next_tag..].starts_with("<script") {
+                "</script"
+            } else {
+                "</style"
+            };
+            lower[next_tag..].find(name).map(|end| next_tag + end)
+        } else {
+            lower[next_tag..].find('>').map(|end| next_tag + end + 1)
+        };
+        pos = end.unwrap_or(html.len());
+    }
+    if text == 0 {
+        0.0
+    } else {
+        japanese as f64 / text as f64
+    }
+}
+
+// For every run, the group it is read in. Runs separated by nothing but inline
+// formatting tags and line breaks share a group, e.g. 大<b>事</b> is read as 大事.
+pub fn inline_groups(html: &str, html_parts: &[HtmlPart]) -> Vec<usize> {
+    let mut groups: Vec<usize> = Vec::new();
+    let mut last_end = None;
+    for part in html_parts.iter().filter(|part| part.need_ruby) {
+        let group = match (last_end, groups.last()) {
+            (Some(end), Some(&group)) if only_inline_tags(&html[end..part.start]) => group,
+            (_, Some(&group)) => group + 1,
+            _ => 0,
+        };
+        groups.push(group);
+        last_end = Some(part.end);
+    }
+    groups
+}
+
+pub fn only_inline_tags(between: &str) -> bool {
+    // a line break inside a sentence does not end it
+    const INLINE_TAGS: [&str; 11] = [
+        "b", "i", "u", "s", "em", "strong", "span", "small", "mark", "br", "wbr",
+    ];
+    let mut rest = between;
+    while !rest.is_empty() {
+        let end = match rest.find('>') {
+            Some(end) if rest.starts_with('<') => end,
+            _ => return false,
+        };
+        let name = rest[1..end]
+            .trim_start_matches('/')
+            .split(|ch: char| ch.is_whitespace() || ch == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        if !INLINE_TAGS.contains(&name.as_str()) {
+            return false;
+        }
+        rest = &rest[end + 1..];
+    }
+    true
+}
+
+// Attach the ASCII letters and digits written right next to a run with kanji to the
+// run, e.g. iPhone版 or 5G回線, so the reading API reads the kanji in context. The
+// ruby stays over the kanji as long as the reading repeats the Latin as written.
+pub fn attach_latin(html: &str, html_parts: &mut Vec<HtmlPart>, jp_runs: &mut [String]) {
+    let mut runs = jp_runs.iter_mut();
+    for i in 0..html_parts.len() {
+        if !html_parts[i].need_ruby {
+            continue;
+        }
+        let run = match runs.next() {
+            Some(run) => run,
+            None => break,
+        };
+        if html_parts[i].in_title || !run.chars().any(|ch| is_cjk_ideograph(&ch)) {
+            continue;
+        }
+        if i > 0 && !html_parts[i - 1].need_ruby {
+            let before = &html[html_parts[i - 1].start..html_parts[i - 1].end];
+            let latin = before
+                .chars()
+                .rev()
+                .take_while(char::is_ascii_alphanumeric)
+                .count();
+            html_parts[i - 1].end -= latin;
+            html_parts[i].start -= latin;
+        }
+        if i + 1 < html_parts.len() && !html_parts[i + 1].need_ruby {
+            let after = &html[html_parts[i + 1].start..html_parts[i + 1].end];
+            let mut latin = after
+                .chars()
+                .take_while(char::is_ascii_alphanumeric)
+                .count();
+            // Latin between two runs goes with the run it comes before, 版と5G回線
+            if latin == after.len() && html_parts.get(i + 2).map_or(false, |next| next.need_ruby) {
+                latin = 0;
+            }
+            html_parts[i + 1].start += latin;
+            html_parts[i].end += latin;
+        }
+        *run = decode_entities(&html[html_parts[i].start..html_parts[i].end]);
+    }
+    html_parts.retain(|part| part.start < part.end);
+}
+
+// leave the runs that match as they are, keeping jp_runs in step with the parts
+pub fn skip_runs<F>(html_parts: &mut [HtmlPart], jp_runs: &mut Vec<String>, mut skip: F)
+where
+    F: FnMut(&HtmlPart, &str) -> bool,
+{
+    let mut kept_runs = Vec::new();
+    let mut runs = jp_runs.drain(..);
+    for part in html_parts.iter_mut().filter(|part| part.need_ruby) {
+        let run = match runs.next() {
+            Some(run) => run,
+            None => break,
+        };
+        if skip(part, &run) {
+            part.need_ruby = false;
+        } else {
+            kept_runs.push(run);
+        }
+    }
+    drop(runs);
+    *jp_runs = kept_runs;
+}
+
+// Keep the first max_runs runs, the text of the others is left as it is; a page made of
+// thousands of tiny runs must not turn into as many lookups. The number of runs left out.
+pub fn cap_runs(html_parts: &mut [HtmlPart], jp_runs: &mut Vec<String>, max_runs: usize) -> usize {
+    let dropped = jp_runs.len().saturating_sub(max_runs);
+    if dropped > 0 {
+        let mut kept = 0;
+        skip_runs(html_parts, jp_runs, |_, _| {
+            kept += 1;
+            kept > max_runs
+        });
+    }
+    dropped
+}
+
+pub fn is_japanese(ch: &char) -> bool {
+    is_cjk_ideograph(ch) || is_hiragana(ch) || is_katakana(ch) || is_halfwidth_katakana(ch)
+}
+
+// ｦ to ﾝ with the prolonged sound mark and the voiced sound marks
+pub fn is_halfwidth_katakana(ch: &char) -> bool {
+    ('\u{ff66}'..='\u{ff9f}').contains(ch)
+}
+
+// Half-width katakana and full-width ASCII as the reading API and the dictionaries
+// expect them, e.g. ｶﾞｲﾄﾞ -> ガイド and ＡＢＣ１ -> ABC1. The page keeps its own forms.
+pub fn fold_width(run: &str) -> String {
+    const HALFWIDTH: &str = "。「」、・ヲァィゥェォャュョッーアイウエオカキクケコサシスセソタチツテトナニヌネノハヒフヘホマミムメモヤユヨラリルレロワン";
+    let mut folded = String::with_capacity(run.len());
+    for ch in run.chars() {
+        match ch {
+            '\u{ff01}'..='\u{ff5e}' => {
+                folded.push(std::char::from_u32(ch as u32 - 0xfee0).unwrap_or(ch))
+            }
+            '\u{3000}' => folded.push(' '),
+            '\u{ff61}'..='\u{ff9d}' => {
+                let index = (ch as u32 - 0xff61) as usize;
+                folded.push(HALFWIDTH.chars().nth(index).unwrap_or(ch));
+            }
+            // the voiced sound marks are separate characters that follow their kana
+            '\u{ff9e}' | '\u{ff9f}' => {
+                let handakuten = ch == '\u{ff9f}';
+                let voiced = folded.chars().last().and_then(|kana| match kana {
+                    'ウ' if !handakuten => Some('ヴ'),
+                    _ if handakuten && "ハヒフヘホ".contains(kana) => {
+                        std::char::from_u32(kana as u32 + 2)
+                    }
+                    _ if !handakuten
+                        && "カキクケコサシスセソタチツテトハヒフヘホ".contains(kana) =>
+                    {
+                        std::char::from_u32(kana as u32 + 1)
+                    }
+                    _ => None,
+                });
+                match voiced {
+                    Some(voiced) => {
+                        folded.pop();
+                        folded.push(voiced);
+                    }
+                    None if handakuten => folded.push('゜'),
+                    None => folded.push('゛'),
+                }
+            }
+            _ => folded.push(ch),
+        }
+    }
+    folded
+}
+
+// kanji::is_kanji stops at the basic block, rarer kanji live in the extensions
+pub fn is_cjk_ideograph(ch: &char) -> bool {
+    match ch {
+        '\u{3005}' | '\u{3007}' => true,   // 々 and 〇
+        '\u{3400}'..='\u{4dbf}' => true,   // Extension A
+        '\u{4e00}'..='\u{9fff}' => true,   // Unified Ideographs
+        '\u{f900}'..='\u{faff}' => true,   // Compatibility Ideographs
+        '\u{20000}'..='\u{2ebef}' => true, // Extensions B to F
+        '\u{2f800}'..='\u{2fa1f}' => true, // Compatibility Ideographs Supplement
+        '\u{30000}'..='\u{3134f}' => true, // Extension G
+        _ => false,
+    }
+}
+
+pub(crate) fn char_at(html_chars: &[char], i: usize) -> (char, usize) {
+    if html_chars[i] == '&' {
+        if let Some(entity) = decode_entity(&html_chars[i..]) {
+            return entity;
+        }
+    }
+    (html_chars[i], 1)
+}
+
+fn decode_entity(html_chars: &[char]) -> Option<(char, usize)> {
+    let end = html_chars.iter().take(12).position(|c| *c == ';')?;
+    let name = html_chars[1..end].iter().collect::<String>();
+    let decoded = if let Some(hex) = name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+        std::char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+    } else if let Some(dec) = name.strip_prefix('#') {
+        std::char::from_u32(dec.parse().ok()?)?
+    } else {
+        match name.as_str() {
+            "amp" => '&',
+            "lt" => '<',
+            "gt" => '>',
+            "quot" => '"',
+            "apos" => '\'',
+            "nbsp" => '\u{a0}',
+            _ => return None,
+        }
+    };
+    Some((decoded, end + 1))
+}
+
+pub fn decode_entities(text: &str) -> String {
+    let text_chars = text.chars().collect::<Vec<char>>();
+    let mut decoded = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < text_chars.len() {
+        let (ch, ch_len) = char_at(&text_chars, i);
+        decoded.push(ch);
+        i += ch_len;
+    }
+    decoded
+}
+
+fn tag_name(tag: &[char]) -> String {
+    let name = tag
+        .iter()
+        .skip(1)
+        .take_while(|c| !c.is_whitespace() && **c != '>')
+        .collect::<String>()
+        .to_lowercase();
+    name.trim_end_matches('/').to_string()
+}
+
+fn update_tag_stack(tag_stack: &mut Vec<String>, name: &str, tag: &[char]) {
+    if let Some(closed) = name.strip_prefix('/') {
+        if let Some(pos) = tag_stack.iter().rposition(|open| open == closed) {
+            tag_stack.truncate(pos);
+        }
+    } else if !name.is_empty()
+        && !name.starts_with('!')
+        && !name.starts_with('?')
+        && !VOID_TAGS.contains(&name)
+        && tag.last() != Some(&'/')
+    {
+        tag_stack.push(name.to_string());
+    }
+}
+
+// the pattern is lowercase ASCII
+fn starts_with_ignore_case(html_chars: &[char], pattern: &str) -> bool {
+    let mut html_chars = html_chars.iter();
+    pattern.chars().all(|expected| {
+        html_chars
+            .next()
+            .map_or(false, |ch| ch.to_ascii_lowercase() == expected)
+    })
+}
+
+fn find_ignore_case(html_chars: &[char], start: usize, pattern: &str) -> Option<usize> {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let found = html_chars
+        .get(start..)?
+        .windows(pattern.len())
+        .position(|window| {
+            window
+                .iter()
+                .zip(&pattern)
+                .all(|(ch, expected)| ch.to_ascii_lowercase() == *expected)
+        });
+    count_steps(found.unwrap_or_else(|| html_chars.len().saturating_sub(start)));
+    found.map(|pos| start + pos)
+}
+
+// the chars the scanner looks at, counted in tests to keep the scan linear
+#[cfg(test)]
+thread_local! {
+    static SCAN_STEPS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+#[cfg(test)]
+fn count_steps(steps: usize) {
+    SCAN_STEPS.with(|count| count.set(count.get() + steps));
+}
+
+#[cfg(not(test))]
+fn count_steps(_steps: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruby::{generate_html_with_ruby, ruby_template, KanjiRuby};
+
+    fn analyze(html: &str) -> (Vec<(&str, bool)>, Vec<String>) {
+        let (parts, runs) = analyze_jp(html, false, &[]);
+        let parts = parts
+            .iter()
+            .map(|part| (&html[part.start..part.end], part.need_ruby))
+            .collect();
+        (parts, runs)
+    }
+
+    #[test]
+    fn text_before_the_first_tag() {
+        assert_eq!(
+            analyze("東京<p>x</p>"),
+            (
+                vec![("東京", true), ("<p>x</p>", false)],
+                vec!["東京".to_string()]
+            )
+        );
+        assert_eq!(
+            analyze("Hi 東京<p>x</p>"),
+            (
+                vec![("Hi ", false), ("東京", true), ("<p>x</p>", false)],
+                vec!["東京".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn text_at_the_end_of_the_document() {
+        assert_eq!(
+            analyze("<p>x</p>大阪"),
+            (
+                vec![("<p>x</p>", false), ("大阪", true)],
+                vec!["大阪".to_string()]
+            )
+        );
+        assert_eq!(
+            analyze("<p>日本</p>abc"),
+            (
+                vec![("<p>", false), ("日本", true), ("</p>abc", false)],
+                vec!["日本".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn adjacent_tags_without_text() {
+        assert_eq!(
+            analyze("<p></p><br><b></b>"),
+            (vec![("<p></p><br><b></b>", false)], vec![])
+        );
+        assert_eq!(
+            analyze("<a href=\"x\">本</a><i>和</i>"),
+            (
+                vec![
+                    ("<a href=\"x\">", false),
+                    ("本", true),
+                    ("</a><i>", false),
+                    ("和", true),
+                    ("</i>", false)
+                ],
+                vec!["本".to_string(), "和".to_string()]
+            )
+        );
+        assert_eq!(analyze(""), (vec![], vec![]));
+    }
+
+    #[test]
+    fn japanese_run_at_the_end_of_the_buffer() {
+        assert_eq!(
+            analyze("<p>日本語"),
+            (
+                vec![("<p>", false), ("日本語", true)],
+                vec!["日本語".to_string()]
+            )
+        );
+        assert_eq!(
+            analyze("日本語"),
+            (vec![("日本語", true)], vec!["日本語".to_string()])
+        );
+    }
+
+    fn large_page(paragraphs: usize) -> String {
+        let mut page = String::from("<html><head><script>");
+        page.push_str(&"var x = '<b>';".repeat(paragraphs));
+        page.push_str("</script></head><body>");
+        for i in 0..paragraphs {
+            page.push_str(&format!("<p class=\"p{}\">東京 {} ok<!-- > --></p>", i, i));
+        }
+        page.push_str("</body></html>");
+        page
+    }
+
+    fn scan_steps(html: &str) -> (usize, Vec<HtmlPart>, Vec<String>) {
+        SCAN_STEPS.with(|count| count.set(0));
+        let (parts, runs) = analyze_jp(html, false, &[]);
+        (SCAN_STEPS.with(|count| count.get()), parts, runs)
+    }
+
+    // the scan is linear: it looks at each char a few times, so four times the page
+    // takes four times the steps, a quadratic scan would take sixteen
+    #[test]
+    fn large_page_is_linear() {
+        let small = large_page(5_000);
+        let large = large_page(20_000);
+        let (small_steps, _, small_runs) = scan_steps(&small);
+        let (large_steps, parts, large_runs) = scan_steps(&large);
+        assert_eq!(small_runs.len(), 5_000);
+        assert_eq!(large_runs.len(), 20_000);
+        assert!(large_runs.iter().all(|run| run == "東京"));
+        assert_eq!(parts.last().map(|part| part.end), Some(large.len()));
+        let large_chars = large.chars().count();
+        assert!(
+            large_steps < large_chars * 3,
+            "{} steps for {} chars",
+            large_steps,
+            large_chars
+        );
+        assert!(
+            large_steps < small_steps * 5,
+            "{} steps for 4x the page, {} for 1x",
+            large_steps,
+            small_steps
+        );
+    }
+
+    // the runs and parts found piece by piece, with the parts moved to page offsets
+    fn analyze_in_pieces(
+        html: &str,
+        piece_len: usize,
+    ) -> (String, Vec<(usize, usize, bool)>, Vec<String>) {
+        let mut analyzer = StreamAnalyzer::new(false, &[]);
+        let (mut text, mut parts, mut runs) = (String::new(), Vec::new(), Vec::new());
+        let chars: Vec<char> = html.chars().collect();
+        let pieces: Vec<String> = chars
+            .chunks(piece_len)
+            .map(|piece| piece.iter().collect())
+            .collect();
+        for (n, piece) in pieces.iter().enumerate() {
+            let (done, done_parts, done_runs) = analyzer.feed(piece, n + 1 == pieces.len());
+            parts.extend(done_parts.iter().filter(|part| part.need_ruby).map(|part| {
+                (
+                    text.len() + part.start,
+                    text.len() + part.end,
+                    part.in_title,
+                )
+            }));
+            assert_eq!(done_parts.last().map_or(0, |part| part.end), done.len());
+            text.push_str(&done);
+            runs.extend(done_runs);
+        }
+        (text, parts, runs)
+    }
+
+    #[test]
+    fn pieces_are_analyzed_like_the_whole_page() {
+        let html = "<html><head><title>日本の&#x65E5;記</title><script>if (a<b) x = '日本';</script>\
+            <style>p > b { color: red }</style></head><body><!-- 東京 > 大阪 -->\
+            <p data-x=\"a>b\">今日は<b>晴れ</b>です。Rust版&amp;京都</p><ruby>漢<rt>かん</rt></ruby>\
+            <pre>コード</pre><textarea>入力</textarea>最後の文</body></html>";
+        let (parts, runs) = analyze_jp(html, false, &[]);
+        let whole_parts: Vec<(usize, usize, bool)> = parts
+            .iter()
+            .filter(|part| part.need_ruby)
+            .map(|part| (part.start, part.end, part.in_title))
+            .collect();
+        for piece_len in 1..html.chars().count() + 1 {
+            let (text, parts, piece_runs) = analyze_in_pieces(html, piece_len);
+            assert_eq!(text, html, "pieces of {}", piece_len);
+            assert_eq!(parts, whole_parts, "pieces of {}", piece_len);
+            assert_eq!(piece_runs, runs, "pieces of {}", piece_len);
+        }
+    }
+
+    #[test]
+    fn a_piece_keeps_only_the_unfinished_tail() {
+        let mut analyzer = StreamAnalyzer::new(false, &[]);
+        let (done, parts, runs) = analyzer.feed("<p>日本語</p><p>東", false);
+        assert_eq!(done, "<p>日本語</p>");
+        assert_eq!(runs, ["日本語"]);
+        assert_eq!(parts.last().map(|part| part.end), Some(done.len()));
+        let (done, _, runs) = analyzer.feed("京</p>", true);
+        assert_eq!(done, "<p>東京</p>");
+        assert_eq!(runs, ["東京"]);
+    }
+
+    #[test]
+    fn katakana_stays_in_the_run_of_its_kanji() {
+        assert_eq!(
+            analyze("<p>新型コロナの流行, ｺﾛﾅ禍</p>"),
+            (
+                vec![
+                    ("<p>", false),
+                    ("新型コロナの流行", true),
+                    (", ", false),
+                    ("ｺﾛﾅ禍", true),
+                    ("</p>", false)
+                ],
+                vec!["新型コロナの流行".to_string(), "ｺﾛﾅ禍".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn extension_b_ideographs_are_kanji() {
+        assert!(is_cjk_ideograph(&'\u{20bb7}'));
+        assert!(is_cjk_ideograph(&'\u{2a6d6}'));
+        assert!(!is_cjk_ideograph(&'\u{1f600}'));
+        // 𠮷 stays in the run, written as it is or as a character reference
+        assert_eq!(
+            analyze("<p>\u{20bb7}野家</p>").1,
+            vec!["\u{20bb7}野家".to_string()]
+        );
+        let (parts, runs) = analyze("<p>&#x20BB7;野家</p>");
+        assert_eq!(parts[1], ("&#x20BB7;野家", true));
+        assert_eq!(runs, vec!["\u{20bb7}野家".to_string()]);
+    }
+
+    #[test]
+    fn void_tags_stay_off_the_tag_stack() {
+        let mut tag_stack = Vec::new();
+        for tag in &["<p>", "<keygen name=\"k\">", "<bgsound>", "<img>", "<b>"] {
+            let tag = tag.chars().collect::<Vec<char>>();
+            update_tag_stack(&mut tag_stack, &tag_name(&tag), &tag);
+        }
+        assert_eq!(tag_stack, vec!["p".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn text_in_skipped_tags_gets_no_ruby() {
+        let skip_tags = ["kbd".to_string(), "my-widget".to_string()];
+        let html = "<p>東京<kbd>大阪<b>奈良</b></kbd><my-widget>京都</my-widget>神戸</p>";
+        let (parts, runs) = analyze_jp(html, false, &skip_tags);
+        let parts = parts
+            .iter()
+            .map(|part| (&html[part.start..part.end], part.need_ruby))
+            .filter(|(text, _)| !text.contains('<'))
+            .collect::<Vec<(&str, bool)>>();
+        assert_eq!(
+            parts,
+            vec![
+                ("東京", true),
+                ("大阪", false),
+                ("奈良", false),
+                ("京都", false),
+                ("神戸", true)
+            ]
+        );
+        assert_eq!(runs, vec!["東京".to_string(), "神戸".to_string()]);
+    }
+
+    #[test]
+    fn japanese_ratio_counts_only_the_text() {
+        // 99 latin letters and one kanji, the comment and script are left out
+        let html = format!(
+            "<html><!-- 日本語 --><script>var s = '漢字';</script><p>{} 日</p></html>",
+            "a".repeat(99)
+        );
+        assert!((japanese_ratio(&html) - 0.01).abs() < 1e-9);
+        assert_eq!(japanese_ratio("<p> </p>"), 0.0);
+    }
+
+    #[test]
+    fn fold_width_to_full_width_kana_and_ascii() {
+        assert_eq!(fold_width("ｶﾞｲﾄﾞ"), "ガイド");
+        assert_eq!(fold_width("ﾊﾟﾝとｳﾞｧ"), "パンとヴァ");
+        assert_eq!(fold_width("ＡＢＣ１　本"), "ABC1 本");
+        // a voiced sound mark without a kana that takes it
+        assert_eq!(fold_width("ｱﾞ"), "ア゛");
+    }
+
+    #[test]
+    fn attach_latin_to_runs_with_kanji() {
+        let html = "<p>iPhone版と5G回線、ABCかな</p>";
+        let (mut parts, mut runs) = analyze_jp(html, false, &[]);
+        attach_latin(html, &mut parts, &mut runs);
+        let parts = parts
+            .iter()
+            .map(|part| (&html[part.start..part.end], part.need_ruby))
+            .collect::<Vec<(&str, bool)>>();
+        assert_eq!(
+            parts,
+            vec![
+                ("<p>", false),
+                ("iPhone版と", true),
+                ("5G回線", true),
+                // かな has no kanji to read in context
+                ("、ABC", false),
+                ("かな", true),
+                ("</p>", false),
+            ]
+        );
+        assert_eq!(runs, vec!["iPhone版と", "5G回線", "かな"]);
+    }
+
+    #[test]
+    fn inline_groups_across_inline_tags() {
+        let html = "<p>大<b>事</b>な<br>本</p><p>京都</p>";
+        let (parts, _) = analyze_jp(html, false, &[]);
+        assert_eq!(inline_groups(html, &parts), vec![0, 0, 0, 0, 1]);
+        let html = "<p>大<a href=\"/\">事</a></p>";
+        let (parts, _) = analyze_jp(html, false, &[]);
+        assert_eq!(inline_groups(html, &parts), vec![0, 1]);
+    }
+
+    #[test]
+    fn mixed_language_text() {
+        assert_eq!(
+            analyze("Hello 世界!"),
+            (
+                vec![("Hello ", false), ("世界", true), ("!", false)],
+                vec!["世界".to_string()]
+            )
+        );
+        assert_eq!(
+            analyze("<p>A世界B日本C</p>"),
+            (
+                vec![
+                    ("<p>A", false),
+                    ("世界", true),
+                    ("B", false),
+                    ("日本", true),
+                    ("C</p>", false),
+                ],
+                vec!["世界".to_string(), "日本".to_string()]
+            )
+        );
+        assert_eq!(
+            analyze("<p>「日本」です。</p>"),
+            (
+                vec![
+                    ("<p>「", false),
+                    ("日本", true),
+                    ("」", false),
+                    ("です", true),
+                    ("。</p>", false),
+                ],
+                vec!["日本".to_string(), "です".to_string()]
+            )
+        );
+        assert_eq!(
+            analyze("<p>Only English.</p>"),
+            (vec![("<p>Only English.</p>", false)], vec![])
+        );
+    }
+
+    #[test]
+    fn runs_past_max_runs_are_left_as_they_are() {
+        let html = "<p>日本</p><p>東京</p><p>大阪</p>";
+        let (mut parts, mut runs) = analyze_jp(html, false, &[]);
+        assert_eq!(cap_runs(&mut parts, &mut runs, 2), 1);
+        assert_eq!(runs, vec!["日本", "東京"]);
+        let generated = generate_html_with_ruby(
+            html,
+            &parts,
+            &runs,
+            &ruby_template("(", ")", false),
+            KanjiRuby::Compound,
+            false,
+            |runs| Ok(runs.iter().map(|_| Some("よみ".to_string())).collect()),
+        )
+        .unwrap();
+        assert!(generated.ends_with("<p>大阪</p>"), "{}", generated);
+        assert_eq!(generated.matches("よみ").count(), 2);
+        assert_eq!(cap_runs(&mut parts, &mut runs, 2), 0);
+    }
+}
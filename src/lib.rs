@@ -0,0 +1,7 @@
+// Finding the Japanese runs of a page and writing their readings, without the
+// Compute@Edge runtime; main.rs adapts them to fastly and the reading API.
+#[cfg(feature = "dom-parser")]
+pub mod dom;
+pub mod html;
+pub mod ruby;
+pub mod scope;
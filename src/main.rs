@@ -1,209 +1,3303 @@
+#[cfg(feature = "dom-parser")]
+use add_ruby::dom;
+#[cfg(not(feature = "dom-parser"))]
+use add_ruby::html::analyze_jp;
+use add_ruby::html::{
+    attach_latin, cap_runs, fold_width, inline_groups, is_cjk_ideograph, japanese_ratio,
+    only_inline_tags, skip_runs, HtmlPart, StreamAnalyzer,
+};
+use add_ruby::ruby::{
+    generate_html_with_ruby, head_or_body_position, insert_in_head, ruby_template, span_template,
+    split_reading, style_markup, to_katakana, KanjiRuby, FURIGANA_STYLE, SPAN_STYLE, WORD_BREAK,
+};
+use add_ruby::scope;
 use anyhow::Result;
 use chrono::Utc;
-use fastly::http::{header, HeaderValue, Method, StatusCode};
+use encoding_rs::{CoderResult, Decoder, Encoding, UTF_8};
+use fastly::http::{HeaderValue, Method, StatusCode};
+use fastly::request::{PendingRequest, PollResult, SendError};
 use fastly::{dictionary::Dictionary, Body, Error, Request, RequestExt, Response, ResponseExt};
-use http::header::{ACCEPT_ENCODING, CONTENT_TYPE, LOCATION};
-use kanji::{is_hiragana, is_kanji, is_katakana};
+use http::header::{
+    HeaderMap, HeaderName, ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION, CACHE_CONTROL,
+    CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, ETAG, HOST, IF_NONE_MATCH, LOCATION,
+    SET_COOKIE, USER_AGENT, VARY,
+};
+use kanji::is_katakana;
+use provider::ReadingProvider;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::fmt::Write;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod compression;
+mod provider;
 
 const API_BACKEND: &str = "labs.goo.ne.jp";
 const BACKEND_NAME: &str = "www.fastly.jp";
 const LOG: &str = "PaperTrail";
+const RUBY_PREF_COOKIE: &str = "ruby_pref";
+const SAVE_DATA: &str = "save-data";
+const X_RUBY_DISABLE: &str = "x-ruby-disable";
+const X_RUBY_APPLIED: &str = "x-ruby-applied";
+const X_RUBY_ERROR: &str = "x-ruby-error";
+const SERVER_TIMING: &str = "server-timing";
+// the client's request headers a followed redirect is sent with
+const REDIRECT_HEADERS: [HeaderName; 5] = [
+    ACCEPT_ENCODING,
+    ACCEPT_LANGUAGE,
+    AUTHORIZATION,
+    COOKIE,
+    USER_AGENT,
+];
+// reading API requests made while serving this request
+static API_CALLS: AtomicUsize = AtomicUsize::new(0);
+// numbers the reading API requests of this request, for their request ids
+static REQUEST_IDS: AtomicUsize = AtomicUsize::new(0);
+const OUTPUT_TYPES: [&str; 3] = ["hiragana", "katakana", "roman"];
+const DEFAULT_OUTPUT_TYPE: &str = "hiragana";
+// upper bound of the time spent waiting between reading API retries
+const MAX_RETRY_DELAY_MS: u64 = 2000;
+// Joins the runs of a batch, their readings are split back at it. Runs hold kanji and
+// kana; a batch with a run that holds a comma anyway is read run by run, as is a batch
+// whose readings do not split back one to one.
+const RUN_DELIMITER: char = ',';
+// the settings that change a transformed page, they are part of its ETag; etag_version
+// is there to be changed by hand, e.g. after editing reading overrides. The limits are
+// here too, they decide which runs get furigana and how a batch is read
+const TRANSFORM_KEYS: [&str; 27] = [
+    "api_max_length",
+    "aria_hidden_reading",
+    "attach_latin",
+    "code_ruby",
+    "debug_comment",
+    "etag_version",
+    "first_occurrence_only",
+    "inject_style",
+    "jlpt_max",
+    "kana_ruby",
+    "kanji_ruby",
+    "max_api_calls_per_request",
+    "max_runs",
+    "merge_inline_runs",
+    "min_japanese_percent",
+    "min_kanji",
+    "output_type",
+    "provider",
+    "render_mode",
+    "rp_close",
+    "rp_open",
+    "ruby_template",
+    "scope_selector",
+    "skip_katakana",
+    "skip_tags",
+    "stylesheet_url",
+    "title_ruby",
+];
+// common words and their readings, generated by build.rs from data/readings.tsv
+include!(concat!(env!("OUT_DIR"), "/readings.rs"));
+// the JLPT level of common kanji, generated by build.rs from data/jlpt.tsv
+include!(concat!(env!("OUT_DIR"), "/jlpt.rs"));
 
 #[derive(Serialize, Deserialize)]
-struct HiraganaResp {
+struct ReadingResp {
     converted: String,
     output_type: String,
     request_id: String,
 }
 
-struct HtmlPart {
-    content: String,
-    need_ruby: bool,
+#[derive(Serialize, Deserialize)]
+struct HealthResp {
+    api_ok: bool,
+    request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RunReport {
+    text: String,
+    offset: usize,
+    length: usize,
+}
+
+// logged as one JSON line per request
+#[derive(Serialize, Default)]
+struct TransformMetrics {
+    url: String,
+    backend_status: u16,
+    backend_ms: u64,
+    runs: usize,
+    api_calls: usize,
+    analyze_ms: u64,
+    api_ms: u64,
+    transform_ms: u64,
+    passthrough: Option<&'static str>,
+}
+
+// logged for every reading API answer with verbose_log, to find the runs that are slow to read
+#[derive(Serialize)]
+struct ApiTiming<'a> {
+    request_id: &'a str,
+    input_chars: usize,
+    status: u16,
+    api_ms: u64,
+}
+
+// not #[fastly::main], a streamed page is sent before its body is written
+fn main() -> Result<(), Error> {
+    fastly::init();
+    match serve(fastly::downstream_request()) {
+        Ok((resp, None)) => resp.send_downstream(),
+        Ok((resp, Some(page_stream))) => {
+            if let Err(e) = page_stream.send(resp) {
+                log::error!("failed to stream the page: {}", e);
+            }
+        }
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))?
+            .send_downstream(),
+    }
+    Ok(())
 }
 
-#[fastly::main]
-fn main(mut req: Request<Body>) -> Result<impl ResponseExt, Error> {
-    // set log endpoint
+fn serve(mut req: Request<Body>) -> Result<(Response<Body>, Option<PageStream>)> {
+    let api_config = Dictionary::open("api_config");
+
+    // set log endpoint, page contents and API traffic are only logged with verbose_log
     fastly::log::set_panic_endpoint(LOG).unwrap();
-    log_fastly::init_simple(LOG, log::LevelFilter::Info);
+    let log_level = if api_config.get("verbose_log").as_deref() == Some("true") {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    log_fastly::init_simple(LOG, log_level);
+
+    let health_path = api_config
+        .get("health_path")
+        .unwrap_or_else(|| "/_ruby/health".to_string());
+    if req.uri().path() == health_path {
+        return Ok((health_check()?, None));
+    }
+    // the stylesheet a stylesheet_url can point at, served without the backend
+    let stylesheet_path = api_config
+        .get("stylesheet_path")
+        .unwrap_or_else(|| "/_ruby/style.css".to_string());
+    if req.uri().path() == stylesheet_path {
+        return Ok((stylesheet(&api_config)?, None));
+    }
+
+    // ?ruby=on|off is remembered in a cookie, the parameter is not passed to the backend
+    let ruby_param = api_config
+        .get("ruby_param")
+        .unwrap_or_else(|| "ruby".to_string());
+    let ruby_query = take_query_param(&mut req, &ruby_param)?;
+    // ?ruby=dry-run reports the runs that would get furigana instead of the page
+    let dry_run = ruby_query.as_deref() == Some("dry-run")
+        || api_config.get("dry_run").as_deref() == Some("true");
+    let ruby_query = ruby_query.filter(|value| value == "on" || value == "off");
+    let ruby_pref = ruby_query.clone().or_else(|| {
+        get_cookie(&req, RUBY_PREF_COOKIE).filter(|value| value == "on" || value == "off")
+    });
+    // Save-Data clients get the page as it is unless they asked for furigana
+    let save_data = req
+        .headers()
+        .get(SAVE_DATA)
+        .and_then(|save_data| save_data.to_str().ok())
+        .map(|save_data| save_data.trim().eq_ignore_ascii_case("on"))
+        .unwrap_or(false);
+    // accept_language leaves the page alone for clients that prefer another language,
+    // for sites that serve several languages at the same URL
+    let language_check = api_config.get("accept_language").as_deref() == Some("true");
+    let other_language = language_check && !prefers_japanese(&req);
+    // an upstream layer can turn furigana off, the header is not passed to the backend
+    let ruby_disabled = req
+        .headers_mut()
+        .remove(X_RUBY_DISABLE)
+        .map(|disable| disable == "1")
+        .unwrap_or(false);
+    let ruby_enabled = !ruby_disabled
+        && match ruby_pref.as_deref() {
+            _ if dry_run => true,
+            Some(ruby_pref) => ruby_pref == "on",
+            None => !save_data && !other_language,
+        };
+
+    // the request headers that chose this variant, for shared caches
+    let mut vary = vec!["X-Ruby-Disable"];
+    if !ruby_disabled && !dry_run {
+        if ruby_query.is_none() {
+            vary.push("Cookie");
+        }
+        if ruby_pref.is_none() {
+            vary.push("Save-Data");
+            if language_check {
+                vary.push("Accept-Language");
+            }
+        }
+    }
+
+    // transform_page adds Accept-Encoding to pages whose coding it chose
+    let (mut resp, page_stream) = handle_request(req, &api_config, ruby_enabled, dry_run)?;
+    add_vary(&mut resp, &vary);
+    if let Some(ruby_query) = ruby_query {
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age=31536000; SameSite=Lax",
+            RUBY_PREF_COOKIE, ruby_query
+        );
+        resp.headers_mut()
+            .append(SET_COOKIE, HeaderValue::from_str(&cookie)?);
+    }
+    Ok((resp, page_stream))
+}
+
+// Set-Cookie values as the client would send them back: a cookie replaces the one of
+// the same name, Max-Age=0 removes it
+fn merge_cookies<'a>(
+    cookie: Option<&str>,
+    set_cookies: impl Iterator<Item = &'a HeaderValue>,
+) -> Option<String> {
+    let cookie_name = |cookie: &str| cookie.split('=').next().unwrap_or("").trim().to_string();
+    let mut cookies = cookie
+        .unwrap_or("")
+        .split(';')
+        .map(|cookie| cookie.trim())
+        .filter(|cookie| !cookie.is_empty())
+        .map(|cookie| cookie.to_string())
+        .collect::<Vec<String>>();
+    for set_cookie in set_cookies.filter_map(|set_cookie| set_cookie.to_str().ok()) {
+        let mut attributes = set_cookie.split(';');
+        let pair = attributes.next().unwrap_or("").trim();
+        let name = cookie_name(pair);
+        if name.is_empty() {
+            continue;
+        }
+        cookies.retain(|cookie| cookie_name(cookie) != name);
+        if !attributes.any(|attribute| attribute.trim().eq_ignore_ascii_case("max-age=0")) {
+            cookies.push(pair.to_string());
+        }
+    }
+    if cookies.is_empty() {
+        None
+    } else {
+        Some(cookies.join("; "))
+    }
+}
+
+// merged into the backend's Vary instead of replacing it
+fn add_vary<T>(resp: &mut Response<T>, vary: &[&str]) {
+    let mut values = resp
+        .headers()
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<String>>();
+    for name in vary {
+        if !values
+            .iter()
+            .any(|value| value == "*" || value.eq_ignore_ascii_case(name))
+        {
+            values.push(name.to_string());
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(&values.join(", ")) {
+        resp.headers_mut().insert(VARY, value);
+    }
+}
+
+// reads a fixed word through the reading API, the content backend is not involved
+fn health_check() -> Result<Response<Body>> {
+    let (status, health_resp) = match get_reading("漢字") {
+        Ok(reading_resp) => (
+            StatusCode::OK,
+            HealthResp {
+                api_ok: true,
+                request_id: Some(reading_resp.request_id),
+            },
+        ),
+        Err(e) => {
+            log::error!("health check failed: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                HealthResp {
+                    api_ok: false,
+                    request_id: None,
+                },
+            )
+        }
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(serde_json::to_string(&health_resp)?))?)
+}
+
+fn stylesheet(api_config: &Dictionary) -> Result<Response<Body>> {
+    let css = api_config.get("stylesheet_css").unwrap_or_else(|| {
+        if api_config.get("render_mode").as_deref() == Some("span") {
+            SPAN_STYLE.to_string()
+        } else {
+            FURIGANA_STYLE.to_string()
+        }
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/css; charset=utf-8")
+        .header(CACHE_CONTROL, "public, max-age=31536000")
+        .body(Body::from(css))?)
+}
+
+fn handle_request(
+    mut req: Request<Body>,
+    api_config: &Dictionary,
+    ruby_enabled: bool,
+    dry_run: bool,
+) -> Result<(Response<Body>, Option<PageStream>)> {
+    let ruby_template = load_ruby_template(api_config);
+    let backend = config_host(api_config, "backend", BACKEND_NAME);
+    let backend_host = config_host(api_config, "backend_host", &backend);
+    let backend_host = request_host(
+        &req,
+        backend_host,
+        api_config.get("preserve_host").as_deref() == Some("true"),
+    );
+    log::debug!("backend: {}, host: {}", backend, backend_host);
 
     // Make any desired changes to the client request.
     req.headers_mut()
-        .insert("Host", HeaderValue::from_static(BACKEND_NAME));
-    req.headers_mut().remove(ACCEPT_ENCODING);
+        .insert("Host", HeaderValue::from_str(&backend_host)?);
+    // only ask for codings that can be decoded here and that the client understands
+    let accepted_encodings = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|accept_encoding| accept_encoding.to_str().ok())
+        .map(compression::accepted_encodings)
+        .unwrap_or_default();
+    if accepted_encodings.is_empty() {
+        req.headers_mut().remove(ACCEPT_ENCODING);
+    } else {
+        req.headers_mut().insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_str(&accepted_encodings.join(", "))?,
+        );
+    }
 
     // We can filter requests that have unexpected methods.
     const VALID_METHODS: [Method; 3] = [Method::HEAD, Method::GET, Method::POST];
     if !(VALID_METHODS.contains(req.method())) {
-        return Ok(Response::builder()
+        let resp = Response::builder()
             .status(StatusCode::METHOD_NOT_ALLOWED)
-            .body(Body::from("This method is not allowed"))?);
+            .body(Body::from("This method is not allowed"))?;
+        return Ok((resp, None));
     }
 
     // Request handling logic could go here...
+    let ruby_enabled = ruby_enabled && path_allowed(api_config, req.uri().path());
+    // the client revalidates transformed pages with their own ETags, the backend
+    // only knows its own
+    let transform_tag = if ruby_enabled && !dry_run {
+        Some(transform_tag(api_config))
+    } else {
+        None
+    };
+    if let Some(ref transform_tag) = transform_tag {
+        backend_if_none_match(&mut req, transform_tag)?;
+    }
     req.set_pass();
-    log::info!("time: {},url: {}", Utc::now(), req.uri());
-    let mut resp = req.send(BACKEND_NAME)?;
-    if resp.status() == StatusCode::MOVED_PERMANENTLY {
-        let re = Regex::new(r"https?://www\.fastly\.jp/.*$").unwrap();
-        let location = resp.headers().get(LOCATION).unwrap().to_str().unwrap();
-        if re.is_match(location) {
-            let req = Request::get(location).body(()).unwrap();
-            resp = req.send(BACKEND_NAME)?;
-        }
-    }
-    if resp.status() == StatusCode::OK && resp.headers().get(CONTENT_TYPE).unwrap() == "text/html" {
-        let body_string = resp.into_body().into_string();
-        log::info!(
-            "time: {}, Get response body from the content site",
-            Utc::now()
-        );
-        let (html_parts, jp_content) = analyze_jp(&body_string);
-        let coverted = generate_html_with_ruby(&html_parts, &jp_content)?;
-        return Ok(Response::builder()
-            .status(StatusCode::OK)
-            .body(Body::from(coverted))?);
+    log::debug!("time: {},url: {}", Utc::now(), req.uri());
+    let url = req.uri().to_string();
+    // a followed 307 or 308 sends the body again
+    let mut method = req.method().clone();
+    let content_type = req.headers().get(CONTENT_TYPE).cloned();
+    let mut redirect_headers = HeaderMap::new();
+    for name in REDIRECT_HEADERS.iter() {
+        for value in req.headers().get_all(name) {
+            redirect_headers.append(name.clone(), value.clone());
+        }
     }
-    Ok(resp)
+    let (req_parts, req_body) = req.into_parts();
+    let mut req_body = req_body.into_bytes();
+    let req = Request::from_parts(req_parts, Body::from(req_body.as_slice()));
+    let fetch_started = Instant::now();
+    let mut resp = req.send(&backend)?;
+    // follow redirects within the site so the final page gets furigana,
+    // any other redirect is passed on to the client
+    const REDIRECTS: [StatusCode; 5] = [
+        StatusCode::MOVED_PERMANENTLY,
+        StatusCode::FOUND,
+        StatusCode::SEE_OTHER,
+        StatusCode::TEMPORARY_REDIRECT,
+        StatusCode::PERMANENT_REDIRECT,
+    ];
+    let redirect_pattern = api_config
+        .get("redirect_pattern")
+        .unwrap_or_else(|| format!(r"^https?://{}/.*$", regex::escape(&backend_host)));
+    let redirect_re = match Regex::new(&redirect_pattern) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            log::error!("invalid redirect_pattern {:?}: {}", redirect_pattern, e);
+            None
+        }
+    };
+    let redirect_backend = config_host(api_config, "redirect_backend", &backend);
+    let max_redirects = config_number(api_config, "max_redirects", 3);
+    let mut redirects = 0;
+    // cookies set by a followed redirect, e.g. a login, still have to reach the client
+    let mut redirect_cookies = Vec::new();
+    while REDIRECTS.contains(&resp.status()) && redirects < max_redirects {
+        let location = match resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+        {
+            Some(location) if location.starts_with("//") => format!("https:{}", location),
+            Some(location) if location.starts_with('/') => {
+                format!("https://{}{}", backend_host, location)
+            }
+            Some(location) => location.to_string(),
+            None => break,
+        };
+        match redirect_re {
+            Some(ref re) if re.is_match(&location) => {}
+            _ => break,
+        }
+        // 307 and 308 repeat the request as it was, a POST becomes a GET on the others
+        if method == Method::POST
+            && resp.status() != StatusCode::TEMPORARY_REDIRECT
+            && resp.status() != StatusCode::PERMANENT_REDIRECT
+        {
+            method = Method::GET;
+            req_body.clear();
+        }
+        log::debug!("following redirect to {} {}", method, location);
+        let mut req = Request::builder()
+            .method(method.clone())
+            .uri(location.as_str());
+        match content_type {
+            Some(ref content_type) if !req_body.is_empty() => {
+                req = req.header(CONTENT_TYPE, content_type);
+            }
+            _ => {}
+        }
+        let mut req = req.body(Body::from(req_body.as_slice()))?;
+        // the followed request is still the client's, with the cookies the redirect set
+        let set_cookies = resp.headers().get_all(SET_COOKIE);
+        let cookie = redirect_headers
+            .get(COOKIE)
+            .and_then(|cookie| cookie.to_str().ok());
+        match merge_cookies(cookie, set_cookies.iter()) {
+            Some(cookie) => {
+                redirect_headers.insert(COOKIE, HeaderValue::from_str(&cookie)?);
+            }
+            None => {
+                redirect_headers.remove(COOKIE);
+            }
+        }
+        redirect_cookies.extend(set_cookies.iter().cloned());
+        req.headers_mut().extend(redirect_headers.clone());
+        // credentials stay with the site they were sent to
+        let location_host = location
+            .parse::<http::Uri>()
+            .ok()
+            .and_then(|uri| uri.host().map(|host| host.to_string()));
+        if location_host.as_deref() != Some(backend_host.as_str()) {
+            req.headers_mut().remove(AUTHORIZATION);
+        }
+        req.set_pass();
+        resp = req.send(&redirect_backend)?;
+        redirects += 1;
+    }
+    for cookie in redirect_cookies {
+        resp.headers_mut().append(SET_COOKIE, cookie);
+    }
+    let mut metrics = TransformMetrics {
+        url,
+        backend_status: resp.status().as_u16(),
+        backend_ms: fetch_started.elapsed().as_millis() as u64,
+        ..Default::default()
+    };
+    let mut page_stream = None;
+    let started = Instant::now();
+    metrics.passthrough = passthrough_reason(ruby_enabled, &method, &resp);
+    let mut resp = if metrics.passthrough.is_some() {
+        resp
+    } else {
+        transform_page(
+            resp,
+            api_config,
+            &ruby_template,
+            &accepted_encodings,
+            dry_run,
+            &mut metrics,
+            &mut page_stream,
+        )?
+    };
+    metrics.transform_ms = started.elapsed().as_millis() as u64;
+    metrics.api_calls = API_CALLS.load(Ordering::Relaxed);
+    // a streamed page is logged once it has been sent
+    if page_stream.is_none() {
+        log::info!("{}", serde_json::to_string(&metrics)?);
+    }
+    if let Some(ref transform_tag) = transform_tag {
+        if resp.status() == StatusCode::NOT_MODIFIED || metrics.passthrough.is_none() {
+            let etag = resp
+                .headers()
+                .get(ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .map(|etag| ruby_etag(etag, transform_tag));
+            if let Some(etag) = etag {
+                resp.headers_mut()
+                    .insert(ETAG, HeaderValue::from_str(&etag)?);
+            }
+        }
+    }
+    let ruby_applied = if metrics.passthrough.is_none() {
+        "true"
+    } else {
+        "false"
+    };
+    resp.headers_mut()
+        .insert(X_RUBY_APPLIED, HeaderValue::from_static(ruby_applied));
+    // server_timing shows browser tools where the time went, it is off as it tells
+    // clients about the backend; a streamed page has not been read yet
+    if api_config.get("server_timing").as_deref() == Some("true") {
+        let mut server_timing = format!(
+            "backend;dur={}, analyze;dur={}",
+            metrics.backend_ms, metrics.analyze_ms
+        );
+        if page_stream.is_none() {
+            server_timing.push_str(&format!(", api;dur={}", metrics.api_ms));
+        }
+        resp.headers_mut()
+            .insert(SERVER_TIMING, HeaderValue::from_str(&server_timing)?);
+    }
+    if let Some(ref mut page_stream) = page_stream {
+        page_stream.metrics = metrics;
+    }
+    Ok((resp, page_stream))
 }
 
-fn analyze_jp(body_string: &str) -> (Vec<HtmlPart>, String) {
-    let chars_num = body_string.chars().count();
-    let html_chars = body_string.chars().collect::<Vec<char>>();
-    let mut i = 0;
-    let mut html_parts = Vec::new();
-    let mut content = "".to_string();
-    let mut jp_content = "".to_string();
-    while i < chars_num {
-        let mut ch = html_chars[i];
-        if ch != '>' {
-            content.push(ch);
-            i += 1;
-            continue;
+fn transform_tag(api_config: &Dictionary) -> String {
+    let mut hasher = DefaultHasher::new();
+    for key in TRANSFORM_KEYS.iter() {
+        key.hash(&mut hasher);
+        api_config.get(key).hash(&mut hasher);
+    }
+    format!("{:x}", hasher.finish())
+}
+
+// Transformed pages get a weak ETag made from the backend's, e.g. "abc" ->
+// W/"abc-ruby1f2e", weak as the coding and the API's readings may change.
+fn ruby_etag(etag: &str, transform_tag: &str) -> String {
+    format!("W/\"{}-ruby{}\"", etag_opaque(etag), transform_tag)
+}
+
+// a page passed through in another coding is the backend's page, but not its bytes
+fn weak_etag(etag: &str) -> String {
+    let etag = etag.trim();
+    if etag.starts_with("W/") {
+        etag.to_string()
+    } else {
+        format!("W/{}", etag)
+    }
+}
+
+fn etag_opaque(etag: &str) -> &str {
+    etag.trim().trim_start_matches("W/").trim_matches('"')
+}
+
+// preserve_host keeps the client's Host for backends that serve several sites
+fn request_host<T>(req: &Request<T>, backend_host: String, preserve_host: bool) -> String {
+    let client_host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .filter(|host| is_host_name(host));
+    match client_host {
+        Some(host) if preserve_host => host.to_string(),
+        _ => backend_host,
+    }
+}
+
+// keep only the ETags of pages transformed with the current settings, as the
+// backend's ETags they were made from; an untransformed copy is not revalidated
+fn backend_if_none_match<T>(req: &mut Request<T>, transform_tag: &str) -> Result<()> {
+    let if_none_match = match req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .and_then(|if_none_match| if_none_match.to_str().ok())
+    {
+        Some(if_none_match) if if_none_match.trim() != "*" => if_none_match.to_string(),
+        _ => return Ok(()),
+    };
+    let suffix = format!("-ruby{}", transform_tag);
+    let etags = if_none_match
+        .split(',')
+        .filter_map(|etag| etag_opaque(etag).strip_suffix(suffix.as_str()))
+        .map(|opaque| format!("W/\"{}\"", opaque))
+        .collect::<Vec<String>>();
+    if etags.is_empty() {
+        req.headers_mut().remove(IF_NONE_MATCH);
+    } else {
+        req.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_str(&etags.join(", "))?);
+    }
+    Ok(())
+}
+
+fn transform_page(
+    resp: Response<Body>,
+    api_config: &Dictionary,
+    ruby_template: &str,
+    accepted_encodings: &[&'static str],
+    dry_run: bool,
+    metrics: &mut TransformMetrics,
+    page_stream: &mut Option<PageStream>,
+) -> Result<Response<Body>> {
+    let content_type = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.to_string());
+    let content_encoding = resp
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|content_encoding| content_encoding.to_str().ok())
+        .map(|content_encoding| content_encoding.trim().to_ascii_lowercase());
+    // large pages could exhaust the memory of the instance, Content-Length tells before
+    // the body is read; without it, and for compressed pages, the body itself is measured
+    let max_html_bytes = config_number(api_config, "max_html_bytes", 5_000_000) as usize;
+    let content_length = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|content_length| content_length.to_str().ok())
+        .and_then(|content_length| content_length.trim().parse::<usize>().ok());
+    if content_length.map_or(false, |content_length| content_length > max_html_bytes) {
+        log::warn!("page is larger than max_html_bytes, passing the page through");
+        metrics.passthrough = Some("too large");
+        return Ok(resp);
+    }
+    if streams(api_config, dry_run) {
+        let (resp, stream) = stream_page(
+            resp,
+            api_config,
+            ruby_template,
+            accepted_encodings,
+            content_type.as_deref(),
+            content_encoding.as_deref(),
+            metrics,
+        )?;
+        *page_stream = stream;
+        return Ok(resp);
+    }
+    let (mut resp_parts, body) = resp.into_parts();
+    let raw_body = body.into_bytes();
+    log::debug!(
+        "time: {}, Get response body from the content site",
+        Utc::now()
+    );
+    let min_japanese_percent = config_number(api_config, "min_japanese_percent", 0);
+    let (body_string, encoding) = match read_page(
+        content_type.as_deref(),
+        content_encoding.as_deref(),
+        raw_body,
+        max_html_bytes,
+        min_japanese_percent,
+    ) {
+        PageBody::Decoded(body_string, encoding) => (body_string, encoding),
+        PageBody::Passthrough(reason, raw_body) => {
+            metrics.passthrough = Some(reason);
+            return Ok(Response::from_parts(resp_parts, Body::from(raw_body)));
         }
-        if ch == '>' {
-            loop {
-                ch = html_chars[i];
-                let next_char;
-                if i + 1 < chars_num {
-                    next_char = html_chars[i + 1]
-                } else {
-                    content.push(ch);
-                    let html_part = HtmlPart {
-                        content: content.clone(),
-                        need_ruby: false,
-                    };
-                    html_parts.push(html_part);
-                    break;
+    };
+    // the parts are ranges of html, the page itself or the page as the DOM parser serializes it
+    let (code_ruby, skip_tags) = analyze_settings(api_config);
+    let analyze_started = Instant::now();
+    #[cfg(feature = "dom-parser")]
+    let (html, mut html_parts, mut jp_runs) =
+        dom::analyze_jp_dom(&body_string, code_ruby, &skip_tags);
+    #[cfg(not(feature = "dom-parser"))]
+    let (mut html_parts, mut jp_runs) = analyze_jp(&body_string, code_ruby, &skip_tags);
+    #[cfg(not(feature = "dom-parser"))]
+    let html = &body_string;
+    let mut run_filter = RunFilter::new(api_config);
+    run_filter.skip(&mut html_parts, &mut jp_runs);
+    // only the elements scope_selector matches get furigana, the whole page when none does
+    if let Some(scope_selector) = api_config.get("scope_selector") {
+        let scopes = scope::scope_ranges(html.as_str(), &scope_selector);
+        if scopes.is_empty() {
+            log::debug!("scope_selector {:?} matches nothing", scope_selector);
+        } else {
+            skip_runs(&mut html_parts, &mut jp_runs, |part, _| {
+                !scopes
+                    .iter()
+                    .any(|scope| scope.start <= part.start && part.end <= scope.end)
+            });
+        }
+    }
+    run_filter.limit(html.as_str(), &mut html_parts, &mut jp_runs);
+    run_filter.log_dropped();
+    metrics.runs = jp_runs.len();
+    metrics.analyze_ms = analyze_started.elapsed().as_millis() as u64;
+    if dry_run {
+        metrics.passthrough = Some("dry run");
+        return run_report(html.as_str(), &html_parts);
+    }
+    // furigana is best effort, serve the page as it is when the reading API fails;
+    // on_api_failure lets operators flag the page or fail the request instead
+    let on_api_failure = api_config.get("on_api_failure");
+    let (kanji_ruby, romaji, merge_inline_runs) = render_settings(api_config);
+    let groups = if merge_inline_runs {
+        inline_groups(html.as_str(), &html_parts)
+    } else {
+        (0..jp_runs.len()).collect()
+    };
+    let head_markup = head_markup(api_config, Some(metrics.runs));
+    let (output_encoding, output_coding) =
+        output_headers(&mut resp_parts.headers, encoding, accepted_encodings)?;
+    let coverted = match generate_html_with_ruby(
+        html.as_str(),
+        &html_parts,
+        &jp_runs,
+        ruby_template,
+        kanji_ruby,
+        romaji,
+        |jp_runs| {
+            let api_started = Instant::now();
+            let readings = get_grouped_readings(jp_runs, &groups);
+            metrics.api_ms = api_started.elapsed().as_millis() as u64;
+            readings
+        },
+    ) {
+        Ok(coverted) if head_markup.is_empty() => coverted,
+        Ok(coverted) => insert_in_head(&coverted, &head_markup),
+        Err(e) => {
+            log::error!("failed to add ruby, passing the page through: {}", e);
+            metrics.passthrough = Some("reading API");
+            let error = if e.is::<ApiTimeout>() {
+                "timeout"
+            } else {
+                "reading API"
+            };
+            match on_api_failure.as_deref() {
+                Some("error") => {
+                    resp_parts
+                        .headers
+                        .insert(X_RUBY_ERROR, HeaderValue::from_static(error));
                 }
-                if next_char == '<' {
-                    if !is_kanji(&ch) && !is_hiragana(&ch) && !is_katakana(&ch) {
-                        content.push(ch);
-                        i += 1;
-                        break;
-                    } else {
-                        content.push(ch);
-                        i += 1;
-                        jp_content = format!("{}{},", jp_content, content);
-
-                        let html_part = HtmlPart {
-                            content: content,
-                            need_ruby: true,
-                        };
-
-                        html_parts.push(html_part);
-                        content = "".to_string();
-                        break;
-                    }
+                Some("fail") => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .header(CACHE_CONTROL, "no-store")
+                        .header(X_RUBY_ERROR, error)
+                        .body(Body::from("Failed to add ruby"))?);
                 }
-                if !is_kanji(&next_char) && !is_hiragana(&next_char) && !is_katakana(&next_char) {
-                    if !is_kanji(&ch) && !is_hiragana(&ch) && !is_katakana(&ch) {
-                        content.push(ch);
-                        i += 1;
-                    } else {
-                        content.push(ch);
-                        i += 1;
-                        jp_content = format!("{}{},", jp_content, content);
-
-                        let html_part = HtmlPart {
-                            content: content,
-                            need_ruby: true,
-                        };
-                        html_parts.push(html_part);
-
-                        content = "".to_string();
+                _ => {}
+            }
+            if let Some(etag) = resp_parts.headers.get(ETAG).cloned() {
+                match etag.to_str().map(weak_etag) {
+                    Ok(etag) => {
+                        resp_parts
+                            .headers
+                            .insert(ETAG, HeaderValue::from_str(&etag)?);
                     }
-                } else {
-                    if !is_kanji(&ch) && !is_hiragana(&ch) && !is_katakana(&ch) {
-                        content.push(ch);
-                        i += 1;
-                        let html_part = HtmlPart {
-                            content: content,
-                            need_ruby: false,
-                        };
-                        html_parts.push(html_part);
-                        content = "".to_string();
-                    } else {
-                        content.push(ch);
-                        i += 1;
+                    Err(_) => {
+                        resp_parts.headers.remove(ETAG);
                     }
                 }
             }
+            body_string
+        }
+    };
+    let coverted = encode_body(
+        &mut resp_parts.headers,
+        &coverted,
+        output_encoding,
+        output_coding,
+    )?;
+    let mut resp = Response::from_parts(resp_parts, Body::from(coverted));
+    add_vary(&mut resp, &["Accept-Encoding"]);
+    Ok(resp)
+}
+
+enum PageBody {
+    Decoded(String, &'static Encoding),
+    // the reason, and the body as the backend sent it
+    Passthrough(&'static str, Vec<u8>),
+}
+
+// The page decoded to UTF-8 from its content coding and charset. Pages are transformed
+// as UTF-8 and encoded back to their own charset, anything that can not be decoded
+// reliably is served as it is.
+fn read_page(
+    content_type: Option<&str>,
+    content_encoding: Option<&str>,
+    raw_body: Vec<u8>,
+    max_html_bytes: usize,
+    min_japanese_percent: u64,
+) -> PageBody {
+    let body_bytes = match compression::decompress(content_encoding, &raw_body, max_html_bytes) {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            log::warn!("{}, passing the page through", e);
+            return PageBody::Passthrough("content encoding", raw_body);
+        }
+    };
+    if body_bytes.len() > max_html_bytes {
+        // decoding stopped at max_html_bytes, the page is passed through as it came
+        log::warn!("page is more than max_html_bytes, passing the page through");
+        return PageBody::Passthrough("too large", raw_body);
+    }
+    let encoding = match detect_encoding(content_type, &body_bytes) {
+        Some(encoding) => encoding,
+        None => {
+            log::warn!("unknown charset, passing the page through");
+            return PageBody::Passthrough("charset", raw_body);
+        }
+    };
+    let (decoded, _, malformed) = encoding.decode(&body_bytes);
+    if malformed {
+        log::warn!(
+            "page is not valid {}, passing the page through",
+            encoding.name()
+        );
+        return PageBody::Passthrough("charset", raw_body);
+    }
+    // min_japanese_percent passes mostly non-Japanese pages through, a few stray
+    // kanji are not worth the reading API calls
+    if min_japanese_percent > 0 {
+        let ratio = japanese_ratio(&decoded);
+        if ratio * 100.0 < min_japanese_percent as f64 {
+            log::debug!("only {:.1}% of the text is Japanese", ratio * 100.0);
+            return PageBody::Passthrough("not japanese", raw_body);
         }
     }
-    return (html_parts, jp_content);
+    PageBody::Decoded(decoded.into_owned(), encoding)
+}
+
+// whitespace matters in <pre> and <code>, they are left alone unless code_ruby is set;
+// skip_tags adds elements such as kbd or custom elements whose text is left alone
+fn analyze_settings(api_config: &Dictionary) -> (bool, Vec<String>) {
+    let code_ruby = api_config.get("code_ruby").as_deref() == Some("true");
+    let skip_tags = api_config
+        .get("skip_tags")
+        .map(|skip_tags| {
+            skip_tags
+                .split(',')
+                .map(|tag| tag.trim().to_ascii_lowercase())
+                .filter(|tag| !tag.is_empty())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+    (code_ruby, skip_tags)
+}
+
+// kanji_ruby, whether romaji follows the kana and whether merge_inline_runs reads runs
+// split only by inline tags, as in 大<b>事</b>, as one text
+fn render_settings(api_config: &Dictionary) -> (KanjiRuby, bool, bool) {
+    let kanji_ruby = match api_config.get("kanji_ruby").as_deref() {
+        Some("true") => KanjiRuby::Split,
+        Some("spaced") => KanjiRuby::Spaced,
+        _ => KanjiRuby::Compound,
+    };
+    // render_mode=romaji writes romaji after the kana, for learners; romaji readings
+    // can not be split back at the run boundaries
+    let roman = api_config.get("output_type").as_deref() == Some("roman");
+    let romaji = api_config.get("render_mode").as_deref() == Some("romaji") && !roman;
+    let merge_inline_runs =
+        api_config.get("merge_inline_runs").as_deref() == Some("true") && !roman;
+    (kanji_ruby, romaji, merge_inline_runs)
 }
 
-fn generate_html_with_ruby(parts: &Vec<HtmlPart>, jp_content: &str) -> Result<String> {
-    let mut html_page = String::new();
-    let hiragana = get_hiragana(jp_content)?;
-    let ruby: Vec<&str> = hiragana.as_str().split(',').collect();
-    let mut i = 0;
-    for part in parts {
-        log::info!("content: {}", part.content);
-        if part.need_ruby {
-            log::info!("<ruby><rb>{}</rb><rt>{}</rt></ruby>", part.content, ruby[i]);
-            write!(
-                &mut html_page,
-                "<ruby><rb>{}</rb><rt>{}</rt></ruby>",
-                part.content, ruby[i]
-            )?;
-            i += 1;
+// The debug comment and the style, they go at the start of <head>. A streamed page
+// has not been read when its head goes out, its runs are not counted.
+fn head_markup(api_config: &Dictionary, runs: Option<usize>) -> String {
+    let mut head_markup = String::new();
+    // lets operators see in the page source that the transform ran
+    if api_config.get("debug_comment").as_deref() == Some("true") {
+        let provider = api_config
+            .get("provider")
+            .unwrap_or_else(|| "goo".to_string());
+        let runs = match runs {
+            Some(runs) => format!("{} runs", runs),
+            None => "streamed".to_string(),
+        };
+        head_markup.push_str(&format!(
+            "<!-- furigana applied: {}, provider={} -->",
+            runs, provider
+        ));
+    }
+    if api_config.get("inject_style").as_deref() != Some("false") {
+        let style = if api_config.get("render_mode").as_deref() == Some("span") {
+            SPAN_STYLE
         } else {
-            write!(&mut html_page, "{}", part.content)?;
+            FURIGANA_STYLE
+        };
+        head_markup.push_str(&style_markup(style, api_config.get("stylesheet_url")));
+    }
+    head_markup
+}
+
+// The charset and coding the page is sent in. The backend's status and headers are
+// kept, only the body and its coding change.
+fn output_headers(
+    headers: &mut HeaderMap,
+    encoding: &'static Encoding,
+    accepted_encodings: &[&'static str],
+) -> Result<(&'static Encoding, Option<&'static str>)> {
+    // encoding_rs writes UTF-16 as UTF-8, Content-Type has to say so
+    let output_encoding = encoding.output_encoding();
+    if output_encoding != encoding {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(|content_type| with_charset(content_type, output_encoding.name()));
+        if let Some(content_type) = content_type {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_str(&content_type)?);
+        }
+    }
+    let output_coding = accepted_encodings.first().cloned();
+    match output_coding {
+        Some(output_coding) => {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(output_coding));
+        }
+        None => {
+            headers.remove(CONTENT_ENCODING);
         }
     }
+    Ok((output_encoding, output_coding))
+}
 
-    Ok(html_page)
+// The settings that leave runs without furigana. skip looks at each run on its own,
+// limit also at the runs before it: a page analyzed in pieces keeps the words it has
+// annotated and the count of its runs from one piece to the next.
+struct RunFilter {
+    skip_katakana: bool,
+    kana_ruby: bool,
+    min_kanji: usize,
+    jlpt_max: Option<u8>,
+    title_ruby: bool,
+    first_occurrence_only: bool,
+    attach_latin: bool,
+    max_runs: usize,
+    annotated: HashSet<String>,
+    runs: usize,
+    dropped: usize,
 }
 
-fn get_hiragana(j: &str) -> Result<String> {
-    let api_config = Dictionary::open("api_config");
-    let app_id = api_config.get("api_id").unwrap();
-    let output_type = api_config.get("output_type").unwrap();
-    let req_body = format!(
-        r#"{{"app_id": "{}","sentence": "{}","output_type": "{}"}}"#,
-        app_id, j, output_type
+impl RunFilter {
+    fn new(api_config: &Dictionary) -> Self {
+        // jlpt_max leaves out runs whose kanji a learner at that level knows
+        let jlpt_max = api_config.get("jlpt_max").and_then(|jlpt_max| {
+            let max = jlpt_level(&jlpt_max);
+            if max.is_none() {
+                log::error!("invalid jlpt_max {:?}, expected N1 to N5", jlpt_max);
+            }
+            max
+        });
+        RunFilter {
+            // katakana words are usually loanwords that are already phonetic
+            skip_katakana: api_config.get("skip_katakana").as_deref() == Some("true"),
+            // a run without kanji reads as it is written, unless readings are wanted in
+            // another script; min_kanji leaves out runs with too few kanji to be hard to read
+            kana_ruby: api_config.get("kana_ruby").as_deref() == Some("true"),
+            min_kanji: config_number(api_config, "min_kanji", 1) as usize,
+            jlpt_max,
+            title_ruby: api_config.get("title_ruby").as_deref() == Some("inline"),
+            // repeats of a word already annotated on the page read as plain text
+            first_occurrence_only: api_config.get("first_occurrence_only").as_deref()
+                == Some("true"),
+            // romaji readings spell the Latin their own way and can not keep the ruby off it
+            attach_latin: api_config.get("attach_latin").as_deref() == Some("true")
+                && api_config.get("output_type").as_deref() != Some("roman"),
+            max_runs: config_number(api_config, "max_runs", 10000) as usize,
+            annotated: HashSet::new(),
+            runs: 0,
+            dropped: 0,
+        }
+    }
+
+    fn skip(&self, html_parts: &mut [HtmlPart], jp_runs: &mut Vec<String>) {
+        skip_runs(html_parts, jp_runs, |part, run| {
+            let skipped_kanji = match run.chars().filter(is_cjk_ideograph).count() {
+                0 => !self.kana_ruby,
+                kanji => kanji < self.min_kanji,
+            };
+            skipped_kanji
+                || (self.skip_katakana && fold_width(run).chars().all(|ch| is_katakana(&ch)))
+                || self.jlpt_max.map_or(false, |max| knows_kanji(run, max))
+                || (part.in_title && !self.title_ruby)
+        });
+    }
+
+    fn limit(&mut self, html: &str, html_parts: &mut Vec<HtmlPart>, jp_runs: &mut Vec<String>) {
+        if self.first_occurrence_only {
+            let annotated = &mut self.annotated;
+            skip_runs(html_parts, jp_runs, |part, run| {
+                !part.in_title && !annotated.insert(fold_width(run))
+            });
+        }
+        if self.attach_latin {
+            attach_latin(html, html_parts, jp_runs);
+        }
+        self.dropped += cap_runs(html_parts, jp_runs, self.max_runs - self.runs);
+        self.runs += jp_runs.len();
+    }
+
+    fn log_dropped(&self) {
+        if self.dropped > 0 {
+            log::warn!(
+                "{} runs, only the first {} get furigana",
+                self.max_runs + self.dropped,
+                self.max_runs
+            );
+        }
+    }
+}
+
+// the page in its own charset and the client's coding; the backend's Content-Length
+// was for the page as it came
+fn encode_body(
+    headers: &mut HeaderMap,
+    html: &str,
+    output_encoding: &'static Encoding,
+    output_coding: Option<&str>,
+) -> Result<Vec<u8>> {
+    let (body, _, _) = output_encoding.encode(html);
+    let body = match output_coding {
+        Some(output_coding) => compression::compress(output_coding, &body)?,
+        None => body.into_owned(),
+    };
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    Ok(body)
+}
+
+// the start of a streamed page that is read for its charset, and the size of the reads
+// after it
+const STREAM_READ_BYTES: usize = 8192;
+
+// stream_response sends the page as it is read. The headers go out first, so it is not
+// used when a failed lookup has to change them, and the settings that need the whole
+// page, scope_selector, min_japanese_percent and the DOM parser, buffer it as before.
+fn streams(api_config: &Dictionary, dry_run: bool) -> bool {
+    api_config.get("stream_response").as_deref() == Some("true")
+        && !matches!(
+            api_config.get("on_api_failure").as_deref(),
+            Some("error") | Some("fail")
+        )
+        && api_config.get("scope_selector").is_none()
+        && config_number(api_config, "min_japanese_percent", 0) == 0
+        && !cfg!(feature = "dom-parser")
+        && !dry_run
+}
+
+// Reads the start of the page for its charset and leaves the rest to a PageStream. A
+// page passed through is sent with the bytes read so far in front of the rest.
+fn stream_page(
+    resp: Response<Body>,
+    api_config: &Dictionary,
+    ruby_template: &str,
+    accepted_encodings: &[&'static str],
+    content_type: Option<&str>,
+    content_encoding: Option<&str>,
+    metrics: &mut TransformMetrics,
+) -> Result<(Response<Body>, Option<PageStream>)> {
+    let (mut resp_parts, mut body) = resp.into_parts();
+    let mut raw_start = Vec::new();
+    let mut start = Vec::new();
+    let read = compression::decoder(content_encoding, Recorder(&mut body, &mut raw_start))
+        .and_then(|decoder| {
+            Ok(decoder
+                .take(STREAM_READ_BYTES as u64)
+                .read_to_end(&mut start)?)
+        });
+    let mut replay = Body::from(raw_start);
+    replay.append(body);
+    if let Err(e) = read {
+        log::warn!("{}, passing the page through", e);
+        metrics.passthrough = Some("content encoding");
+        return Ok((Response::from_parts(resp_parts, replay), None));
+    }
+    // a character cut at the end of the start is still UTF-8
+    let complete = match std::str::from_utf8(&start) {
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        _ => start.len(),
+    };
+    let encoding = match detect_encoding(content_type, &start[..complete]) {
+        Some(encoding) => encoding,
+        None => {
+            log::warn!("unknown charset, passing the page through");
+            metrics.passthrough = Some("charset");
+            return Ok((Response::from_parts(resp_parts, replay), None));
+        }
+    };
+    if decode_piece(&mut encoding.new_decoder(), &start, false).1 {
+        log::warn!(
+            "page is not valid {}, passing the page through",
+            encoding.name()
+        );
+        metrics.passthrough = Some("charset");
+        return Ok((Response::from_parts(resp_parts, replay), None));
+    }
+    let (code_ruby, skip_tags) = analyze_settings(api_config);
+    let (kanji_ruby, romaji, merge_inline_runs) = render_settings(api_config);
+    let head_markup = head_markup(api_config, None);
+    let (output_encoding, output_coding) =
+        output_headers(&mut resp_parts.headers, encoding, accepted_encodings)?;
+    resp_parts.headers.remove(CONTENT_LENGTH);
+    let page_stream = PageStream {
+        body: Some(replay),
+        content_encoding: content_encoding.map(|content_encoding| content_encoding.to_string()),
+        encoding,
+        analyzer: StreamAnalyzer::new(code_ruby, &skip_tags),
+        run_filter: RunFilter::new(api_config),
+        ruby_template: ruby_template.to_string(),
+        kanji_ruby,
+        romaji,
+        merge_inline_runs,
+        head_markup: Some(head_markup).filter(|head_markup| !head_markup.is_empty()),
+        held: String::new(),
+        window: Window::default(),
+        output_encoding,
+        output_coding,
+        max_length: config_number(api_config, "api_max_length", 1000).max(1) as usize,
+        metrics: TransformMetrics::default(),
+    };
+    let mut resp = Response::from_parts(resp_parts, Body::new());
+    add_vary(&mut resp, &["Accept-Encoding"]);
+    Ok((resp, Some(page_stream)))
+}
+
+// reads through to a body and keeps the bytes it read
+struct Recorder<'a, R>(&'a mut R, &'a mut Vec<u8>);
+
+impl<'a, R: Read> Read for Recorder<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.0.read(buf)?;
+        self.1.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// decodes the next bytes of a page, and whether any of them were not valid
+fn decode_piece(decoder: &mut Decoder, bytes: &[u8], last: bool) -> (String, bool) {
+    let mut piece = String::with_capacity(
+        decoder
+            .max_utf8_buffer_length(bytes.len())
+            .unwrap_or(bytes.len()),
     );
+    let mut malformed = false;
+    let mut read = 0;
+    loop {
+        let (result, decoded, replaced) =
+            decoder.decode_to_string(&bytes[read..], &mut piece, last);
+        read += decoded;
+        malformed |= replaced;
+        match result {
+            CoderResult::InputEmpty => return (piece, malformed),
+            CoderResult::OutputFull => piece.reserve(bytes.len() - read + 16),
+        }
+    }
+}
+
+// A transformed page sent as it is read from the backend, with stream_response. The
+// markup goes out once it is decoded and analyzed; what is held is the page before
+// the head markup can be placed, and a window from the first run still waiting for
+// its reading. A window has about as many runs as one reading API call reads, and
+// the runs read as one text stay in one window.
+struct PageStream {
+    // the rest of the backend's body, until the page is sent
+    body: Option<Body>,
+    content_encoding: Option<String>,
+    encoding: &'static Encoding,
+    analyzer: StreamAnalyzer,
+    run_filter: RunFilter,
+    ruby_template: String,
+    kanji_ruby: KanjiRuby,
+    romaji: bool,
+    merge_inline_runs: bool,
+    // until it is written
+    head_markup: Option<String>,
+    held: String,
+    window: Window,
+    output_encoding: &'static Encoding,
+    output_coding: Option<&'static str>,
+    max_length: usize,
+    metrics: TransformMetrics,
+}
+
+// the parts are ranges of html, the length counts the run characters as a reading
+// API call does
+#[derive(Default)]
+struct Window {
+    html: String,
+    html_parts: Vec<HtmlPart>,
+    jp_runs: Vec<String>,
+    length: usize,
+    last_run_end: usize,
+}
+
+impl Window {
+    fn push(&mut self, text: &str, need_ruby: bool, in_title: bool) {
+        self.html_parts.push(HtmlPart {
+            start: self.html.len(),
+            end: self.html.len() + text.len(),
+            need_ruby,
+            in_title,
+        });
+        self.html.push_str(text);
+    }
+}
+
+impl PageStream {
+    fn send(mut self, resp: Response<Body>) -> Result<()> {
+        let started = Instant::now();
+        let mut out = compression::encoder(self.output_coding, resp.send_downstream_streaming())?;
+        let body = self.body.take().unwrap_or_else(Body::new);
+        let mut api_ms = 0;
+        self.transform(body, &mut out, |jp_runs, groups| {
+            let api_started = Instant::now();
+            let readings = get_grouped_readings(jp_runs, groups);
+            api_ms += api_started.elapsed().as_millis() as u64;
+            readings
+        })?;
+        // finishes the coding and closes the stream
+        drop(out);
+        self.metrics.api_ms = api_ms;
+        self.metrics.transform_ms += started.elapsed().as_millis() as u64;
+        self.metrics.api_calls = API_CALLS.load(Ordering::Relaxed);
+        log::info!("{}", serde_json::to_string(&self.metrics)?);
+        Ok(())
+    }
+
+    fn transform<R, W, F>(&mut self, body: R, out: &mut W, mut get_readings: F) -> Result<()>
+    where
+        R: Read,
+        W: Write,
+        F: FnMut(&[String], &[usize]) -> Result<Vec<Option<String>>>,
+    {
+        let mut body = compression::decoder(self.content_encoding.as_deref(), body)?;
+        let mut decoder = self.encoding.new_decoder();
+        let mut buf = vec![0; STREAM_READ_BYTES];
+        let mut malformed = false;
+        loop {
+            let read = body.read(&mut buf)?;
+            let last = read == 0;
+            // the start of the page was valid, the rest can not be passed through anymore
+            let (piece, replaced) = decode_piece(&mut decoder, &buf[..read], last);
+            if replaced && !malformed {
+                log::warn!(
+                    "page is not valid {}, streaming it as decoded",
+                    self.encoding.name()
+                );
+                malformed = true;
+            }
+            let (html, mut html_parts, mut jp_runs) = self.analyzer.feed(&piece, last);
+            self.run_filter.skip(&mut html_parts, &mut jp_runs);
+            self.run_filter.limit(&html, &mut html_parts, &mut jp_runs);
+            self.metrics.runs += jp_runs.len();
+            let mut jp_runs = jp_runs.into_iter();
+            for part in html_parts {
+                let text = &html[part.start..part.end];
+                let run = if part.need_ruby { jp_runs.next() } else { None };
+                match run {
+                    Some(run) => self.push_run(out, text, part.in_title, run, &mut get_readings)?,
+                    None if self.window.jp_runs.is_empty() => self.write(out, text)?,
+                    None => self.window.push(text, false, false),
+                }
+            }
+            // the leading markup goes out while the rest of the page is read, once it
+            // has the place of the head markup
+            if self.head_markup.is_some() && head_or_body_position(&self.held).is_some() {
+                self.write_head(out)?;
+            }
+            if last {
+                break;
+            }
+            out.flush()?;
+        }
+        self.write_window(out, &mut get_readings)?;
+        self.write_head(out)?;
+        self.run_filter.log_dropped();
+        Ok(())
+    }
+
+    // a run that does not fit in the window sends the window first, unless it is read
+    // with the runs before it
+    fn push_run<W, F>(
+        &mut self,
+        out: &mut W,
+        text: &str,
+        in_title: bool,
+        run: String,
+        get_readings: &mut F,
+    ) -> Result<()>
+    where
+        W: Write,
+        F: FnMut(&[String], &[usize]) -> Result<Vec<Option<String>>>,
+    {
+        self.write_head(out)?;
+        let run_length = run.chars().count() + 1;
+        let window = &self.window;
+        let grouped = self.merge_inline_runs
+            && !window.jp_runs.is_empty()
+            && only_inline_tags(&window.html[window.last_run_end..]);
+        if window.length + run_length > self.max_length && !grouped {
+            self.write_window(out, get_readings)?;
+        }
+        let window = &mut self.window;
+        window.push(text, true, in_title);
+        window.jp_runs.push(run);
+        window.length += run_length;
+        window.last_run_end = window.html.len();
+        Ok(())
+    }
+
+    // after a failed lookup the rest of the page is sent as it is
+    fn write_window<W, F>(&mut self, out: &mut W, get_readings: &mut F) -> Result<()>
+    where
+        W: Write,
+        F: FnMut(&[String], &[usize]) -> Result<Vec<Option<String>>>,
+    {
+        let mut window = std::mem::take(&mut self.window);
+        if window.jp_runs.is_empty() {
+            return Ok(());
+        }
+        if self.metrics.passthrough.is_none() {
+            let groups = if self.merge_inline_runs {
+                inline_groups(&window.html, &window.html_parts)
+            } else {
+                (0..window.jp_runs.len()).collect()
+            };
+            let generated = generate_html_with_ruby(
+                &window.html,
+                &window.html_parts,
+                &window.jp_runs,
+                &self.ruby_template,
+                self.kanji_ruby,
+                self.romaji,
+                |jp_runs| get_readings(jp_runs, &groups),
+            );
+            match generated {
+                Ok(generated) => window.html = generated,
+                Err(e) => {
+                    log::error!("failed to add ruby, streaming the rest as it is: {}", e);
+                    self.metrics.passthrough = Some("reading API");
+                }
+            }
+        }
+        self.write(out, &window.html)?;
+        out.flush()?;
+        Ok(())
+    }
 
-    log::info!("{}", &req_body);
+    // the page is held until the head markup is placed in it: at its <head> or <body>,
+    // or without them at the first run or the end of the page
+    fn write<W: Write>(&mut self, out: &mut W, html: &str) -> Result<()> {
+        if self.head_markup.is_some() {
+            self.held.push_str(html);
+        } else {
+            out.write_all(&self.output_encoding.encode(html).0)?;
+        }
+        Ok(())
+    }
+
+    fn write_head<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        if let Some(head_markup) = self.head_markup.take() {
+            let held = std::mem::take(&mut self.held);
+            self.write(out, &insert_in_head(&held, &head_markup))?;
+        }
+        Ok(())
+    }
+}
+
+// offsets are in bytes of the analyzed page, decoded to UTF-8
+fn run_report(html: &str, html_parts: &[HtmlPart]) -> Result<Response<Body>> {
+    let runs = html_parts
+        .iter()
+        .filter(|part| part.need_ruby)
+        .map(|part| RunReport {
+            text: html[part.start..part.end].to_string(),
+            offset: part.start,
+            length: part.end - part.start,
+        })
+        .collect::<Vec<RunReport>>();
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(Body::from(serde_json::to_string(&runs)?))?)
+}
+
+fn detect_encoding(content_type: Option<&str>, body: &[u8]) -> Option<&'static Encoding> {
+    let label = content_type
+        .and_then(charset_label)
+        .or_else(|| meta_charset(body));
+    match label {
+        Some(label) => Encoding::for_label(label.as_bytes()),
+        None if std::str::from_utf8(body).is_ok() => Some(UTF_8),
+        None => None,
+    }
+}
+
+fn charset_label(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let param = param.trim();
+        if param.len() > 8 && param[..8].eq_ignore_ascii_case("charset=") {
+            Some(
+                param[8..]
+                    .trim_matches(|c| c == '"' || c == '\'')
+                    .to_string(),
+            )
+        } else {
+            None
+        }
+    })
+}
+
+// replaces the charset parameter of a Content-Type, or adds one
+fn with_charset(content_type: &str, charset: &str) -> String {
+    let mut params = content_type
+        .split(';')
+        .map(|param| param.trim())
+        .filter(|param| {
+            !param.is_empty()
+                && !param
+                    .get(..8)
+                    .map_or(false, |name| name.eq_ignore_ascii_case("charset="))
+        })
+        .collect::<Vec<&str>>();
+    let charset = format!("charset={}", charset);
+    params.push(&charset);
+    params.join("; ")
+}
+
+// <meta charset="..."> or <meta http-equiv="Content-Type" content="...; charset=...">
+fn meta_charset(body: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&body[..body.len().min(1024)]).to_ascii_lowercase();
+    let start = head.find("charset=")? + "charset=".len();
+    let label = head[start..]
+        .trim_start_matches(|c| c == '"' || c == '\'')
+        .split(|c: char| c == '"' || c == '\'' || c == ';' || c == '>' || c.is_whitespace())
+        .next()?;
+    if label.is_empty() {
+        None
+    } else {
+        Some(label.to_string())
+    }
+}
+
+// Remove a parameter from the query string and return its value.
+fn take_query_param(req: &mut Request<Body>, name: &str) -> Result<Option<String>> {
+    let query = match req.uri().query() {
+        Some(query) => query.to_string(),
+        None => return Ok(None),
+    };
+    let mut value = None;
+    let kept = query
+        .split('&')
+        .filter(|pair| {
+            let mut key_value = pair.splitn(2, '=');
+            if key_value.next() == Some(name) {
+                value = Some(key_value.next().unwrap_or("").to_string());
+                false
+            } else {
+                true
+            }
+        })
+        .collect::<Vec<&str>>();
+    if value.is_some() {
+        let uri = req.uri().to_string();
+        let base = &uri[..uri.find('?').unwrap_or(uri.len())];
+        let uri = if kept.is_empty() {
+            base.to_string()
+        } else {
+            format!("{}?{}", base, kept.join("&"))
+        };
+        *req.uri_mut() = uri.parse()?;
+    }
+    Ok(value)
+}
+
+// path_allow and path_deny are comma separated lists of path prefixes, an entry
+// starting with ~ is a regex matched against the whole path instead
+fn path_allowed(api_config: &Dictionary, path: &str) -> bool {
+    let allowed = match api_config.get("path_allow") {
+        Some(allow) => path_matches(&allow, path),
+        None => true,
+    };
+    let denied = match api_config.get("path_deny") {
+        Some(deny) => path_matches(&deny, path),
+        None => false,
+    };
+    allowed && !denied
+}
+
+fn path_matches(patterns: &str, path: &str) -> bool {
+    patterns
+        .split(',')
+        .map(|pattern| pattern.trim())
+        .filter(|pattern| !pattern.is_empty())
+        .any(|pattern| match pattern.strip_prefix('~') {
+            Some(re) => match Regex::new(&format!("^(?:{})$", re)) {
+                Ok(re) => re.is_match(path),
+                Err(e) => {
+                    log::error!("invalid path pattern {:?}: {}", pattern, e);
+                    false
+                }
+            },
+            None => path.starts_with(pattern),
+        })
+}
+
+// the language with the highest q in Accept-Language is Japanese, or there is no
+// preference; ja;q=0 refuses Japanese
+fn prefers_japanese<T>(req: &Request<T>) -> bool {
+    let accept_language = match req
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|accept_language| accept_language.to_str().ok())
+    {
+        Some(accept_language) => accept_language,
+        None => return true,
+    };
+    let mut preferred: Option<(&str, f32)> = None;
+    for item in accept_language.split(',') {
+        let mut params = item.split(';');
+        let language = params.next().unwrap_or("").trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .next()
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if language.is_empty() {
+            continue;
+        }
+        if q <= 0.0 {
+            if primary_language(language).eq_ignore_ascii_case("ja") {
+                return false;
+            }
+            continue;
+        }
+        if preferred.map_or(true, |(_, best)| q > best) {
+            preferred = Some((language, q));
+        }
+    }
+    match preferred {
+        Some((language, _)) => {
+            let primary = primary_language(language);
+            primary == "*" || primary.eq_ignore_ascii_case("ja")
+        }
+        None => true,
+    }
+}
+
+fn primary_language(language: &str) -> &str {
+    language.split('-').next().unwrap_or("")
+}
 
-    let req = Request::builder()
-        .method(Method::POST)
-        .header(header::CONTENT_TYPE, "application/json")
-        .uri("https://labs.goo.ne.jp/api/hiragana")
-        .body(Body::from(req_body))?;
+fn get_cookie(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            let mut name_value = cookie.trim().splitn(2, '=');
+            if name_value.next() == Some(name) {
+                name_value.next().map(|value| value.to_string())
+            } else {
+                None
+            }
+        })
+}
 
-    let resp = req.send(API_BACKEND)?;
+// responses without a Content-Type are passed through untouched
+// why the backend's response goes to the client as it is, None when it gets furigana
+fn passthrough_reason<T>(
+    ruby_enabled: bool,
+    method: &Method,
+    resp: &Response<T>,
+) -> Option<&'static str> {
+    if !ruby_enabled {
+        Some("ruby off")
+    } else if *method == Method::HEAD {
+        // there is no body, and the headers describe the page as the backend serves it
+        Some("head")
+    } else if resp.status() != StatusCode::OK || !is_html(resp) {
+        Some("not html")
+    } else if has_no_transform(resp) {
+        log::info!("Cache-Control: no-transform, not adding furigana");
+        Some("no-transform")
+    } else {
+        None
+    }
+}
 
-    let body_str = resp.into_body().into_string();
+fn is_html<T>(resp: &Response<T>) -> bool {
+    resp.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+        .unwrap_or(false)
+}
 
-    log::info!("{}", &body_str);
+// the backend asks intermediaries not to change the body, RFC 7234 5.2.2.4
+fn has_no_transform<T>(resp: &Response<T>) -> bool {
+    resp.headers()
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|cache_control| cache_control.to_str().ok())
+        .flat_map(|cache_control| cache_control.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
 
-    let hiragana_resp: HiraganaResp = serde_json::from_str(&body_str)?;
+fn load_ruby_template(api_config: &Dictionary) -> String {
+    if let Some(template) = api_config.get("ruby_template") {
+        if template.contains("{base}") && template.contains("{reading}") {
+            return template;
+        }
+        log::error!(
+            "ruby_template must contain {{base}} and {{reading}}, using the default: {}",
+            template
+        );
+    }
+    // screen readers read the base only, unless aria_hidden_reading is turned off
+    let aria_hidden = api_config.get("aria_hidden_reading").as_deref() != Some("false");
+    if api_config.get("render_mode").as_deref() == Some("span") {
+        return span_template(aria_hidden);
+    }
+    // <rp> shows the reading in brackets where ruby is not supported
+    let rp_open = api_config.get("rp_open").unwrap_or_else(|| "(".to_string());
+    let rp_close = api_config
+        .get("rp_close")
+        .unwrap_or_else(|| ")".to_string());
+    ruby_template(&rp_open, &rp_close, aria_hidden)
+}
 
-    Ok(hiragana_resp.converted)
+// The runs of a group are looked up as one text and its reading is split back at
+// the run boundaries. A group whose reading can not be split, e.g. 東<b>京</b>, is
+// looked up run by run.
+fn get_grouped_readings(jp_runs: &[String], groups: &[usize]) -> Result<Vec<Option<String>>> {
+    let mut group_runs: Vec<Vec<String>> = Vec::new();
+    for (run, &group) in jp_runs.iter().zip(groups) {
+        if group == group_runs.len() {
+            group_runs.push(Vec::new());
+        }
+        group_runs[group].push(run.clone());
+    }
+    let texts = group_runs
+        .iter()
+        .map(|runs| runs.concat())
+        .collect::<Vec<String>>();
+    let mut readings = Vec::with_capacity(jp_runs.len());
+    let mut unsplit = Vec::new();
+    for (runs, reading) in group_runs.iter().zip(get_readings(&texts)?) {
+        let reading = match reading {
+            Some(reading) => reading,
+            None => {
+                readings.extend(runs.iter().map(|_| None));
+                continue;
+            }
+        };
+        if runs.len() == 1 {
+            readings.push(Some(reading));
+            continue;
+        }
+        match split_reading(runs, &reading) {
+            Some(split) => readings.extend(split.into_iter().map(Some)),
+            None => {
+                log::debug!("can not split {} over {:?}", reading, runs);
+                for run in runs {
+                    unsplit.push((readings.len(), run.clone()));
+                    readings.push(None);
+                }
+            }
+        }
+    }
+    if !unsplit.is_empty() {
+        let runs = unsplit
+            .iter()
+            .map(|(_, run)| run.clone())
+            .collect::<Vec<String>>();
+        for ((i, _), reading) in unsplit.into_iter().zip(get_readings(&runs)?) {
+            readings[i] = reading;
+        }
+    }
+    Ok(readings)
+}
+
+// operator overrides and common words come from dictionaries and only the rest
+// from the API
+fn get_readings(jp_runs: &[String]) -> Result<Vec<Option<String>>> {
+    let api_config = Dictionary::open("api_config");
+    let output_type = api_config
+        .get("output_type")
+        .unwrap_or_else(|| DEFAULT_OUTPUT_TYPE.to_string());
+    read_runs(
+        jp_runs,
+        &|run| override_reading(&api_config, run).or_else(|| local_reading(run, &output_type)),
+        &mut lookup_readings,
+    )
+}
+
+// reads runs somewhere else, e.g. from the reading API
+type Lookup<'a> = dyn FnMut(&[String]) -> Result<Vec<Option<String>>> + 'a;
+
+// pages repeat the same words, every distinct run is read once, from local_reading
+// when it knows the run and otherwise in one lookup with the other runs
+fn read_runs(
+    jp_runs: &[String],
+    local_reading: &dyn Fn(&str) -> Option<String>,
+    lookup: &mut Lookup,
+) -> Result<Vec<Option<String>>> {
+    let mut readings = Vec::new();
+    let mut remote_runs = Vec::new();
+    let mut remote_index = Vec::new();
+    let mut run_index = HashMap::new();
+    let folded_runs = jp_runs
+        .iter()
+        .map(|run| fold_width(run))
+        .collect::<Vec<String>>();
+    for run in &folded_runs {
+        run_index.entry(run.as_str()).or_insert_with(|| {
+            let reading = local_reading(run);
+            if reading.is_none() {
+                remote_runs.push(run.clone());
+                remote_index.push(readings.len());
+            }
+            readings.push(reading);
+            readings.len() - 1
+        });
+    }
+    if !remote_runs.is_empty() {
+        match lookup(&remote_runs) {
+            Ok(remote_readings) => {
+                for (&i, reading) in remote_index.iter().zip(remote_readings) {
+                    readings[i] = reading;
+                }
+            }
+            // without the API only the words in the local dictionary get furigana
+            Err(e) if remote_index.len() < readings.len() => {
+                log::error!("reading API failed, using the local dictionary only: {}", e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    // a run with attach_latin keeps its reading only when the reading repeats the
+    // Latin as written, otherwise the kanji are looked up on their own
+    let mut bare_runs = Vec::new();
+    let mut bare_index = Vec::new();
+    for (run, &i) in remote_runs.iter().zip(&remote_index) {
+        let (prefix, bare, suffix) = split_latin(run);
+        let reading = match &readings[i] {
+            Some(reading) if !prefix.is_empty() || !suffix.is_empty() => {
+                reading.replace(WORD_BREAK, "")
+            }
+            _ => continue,
+        };
+        if !reading.starts_with(prefix) || !reading.ends_with(suffix) {
+            bare_runs.push(bare.to_string());
+            bare_index.push((i, prefix, suffix));
+        }
+    }
+    if !bare_runs.is_empty() {
+        let bare_readings = read_runs(&bare_runs, local_reading, lookup)?;
+        for ((i, prefix, suffix), reading) in bare_index.into_iter().zip(bare_readings) {
+            readings[i] = reading.map(|reading| format!("{}{}{}", prefix, reading, suffix));
+        }
+    }
+    Ok(folded_runs
+        .iter()
+        .map(|run| readings[run_index[run.as_str()]].clone())
+        .collect())
+}
+
+// the ASCII letters and digits attach_latin put before and after the kanji of a run
+fn split_latin(run: &str) -> (&str, &str, &str) {
+    let start = run.len()
+        - run
+            .trim_start_matches(|ch: char| ch.is_ascii_alphanumeric())
+            .len();
+    let end = run
+        .trim_end_matches(|ch: char| ch.is_ascii_alphanumeric())
+        .len();
+    if start >= end {
+        return (run, "", "");
+    }
+    (&run[..start], &run[start..end], &run[end..])
+}
+
+// N3 or 3 -> 3
+fn jlpt_level(level: &str) -> Option<u8> {
+    let level = level.trim();
+    let level = level
+        .strip_prefix('N')
+        .or_else(|| level.strip_prefix('n'))
+        .unwrap_or(level);
+    level.parse().ok().filter(|level| (1..=5).contains(level))
+}
+
+// 5 for N5 to 1 for N1, kanji outside data/jlpt.tsv are harder than N1
+fn kanji_level(ch: char) -> u8 {
+    let mut buf = [0; 4];
+    JLPT_LEVELS
+        .get(ch.encode_utf8(&mut buf) as &str)
+        .cloned()
+        .unwrap_or(0)
+}
+
+// a reader at level max knows the kanji of the run, and it has some; kana_ruby runs
+// without kanji still get their readings
+fn knows_kanji(run: &str, max: u8) -> bool {
+    let mut kanji = run.chars().filter(is_cjk_ideograph).peekable();
+    kanji.peek().is_some() && kanji.all(|ch| kanji_level(ch) >= max)
+}
+
+// names and terms the API gets wrong, e.g. `reading:日本橋` -> にほんばし; the
+// reading is used as it is whatever the output_type
+fn override_reading(api_config: &Dictionary, run: &str) -> Option<String> {
+    api_config
+        .get(&format!("reading:{}", run))
+        .filter(|reading| !reading.trim().is_empty())
+}
+
+// the dictionary has hiragana readings, romaji is left to the API
+fn local_reading(run: &str, output_type: &str) -> Option<String> {
+    let reading = LOCAL_READINGS.get(run)?;
+    match output_type {
+        "hiragana" => Some(reading.to_string()),
+        "katakana" => Some(reading.chars().map(to_katakana).collect()),
+        _ => None,
+    }
+}
+
+// the API has an input limit, runs are sent in batches of up to api_max_length characters
+// the runs left when max_api_calls_per_request is used up get no reading
+fn lookup_readings(jp_runs: &[String]) -> Result<Vec<Option<String>>> {
+    let api_config = Dictionary::open("api_config");
+    let max_length = config_number(&api_config, "api_max_length", 1000).max(1) as usize;
+    read_batches(jp_runs, max_length, get_reading_all)
+}
+
+// Every batch is read with one API call, the calls are sent together through
+// read_sentences. The runs of a batch whose readings can not be matched back to them
+// one to one, and runs longer than one call takes, are read again run by run.
+fn read_batches<F>(
+    jp_runs: &[String],
+    max_length: usize,
+    mut read_sentences: F,
+) -> Result<Vec<Option<String>>>
+where
+    F: FnMut(&[String]) -> Result<Vec<Option<ReadingResp>>>,
+{
+    let mut batches = Vec::new();
+    let mut batch_start = 0;
+    let mut batch_length = 0;
+    for (i, run) in jp_runs.iter().enumerate() {
+        let length = run.chars().count() + 1;
+        if i > batch_start && batch_length + length > max_length {
+            batches.push(batch_start..i);
+            batch_start = i;
+            batch_length = 0;
+        }
+        batch_length += length;
+    }
+    if batch_start < jp_runs.len() {
+        batches.push(batch_start..jp_runs.len());
+    }
+    let sentences = batches
+        .iter()
+        .map(|batch| {
+            let runs = &jp_runs[batch.clone()];
+            let sentence = runs.join(&RUN_DELIMITER.to_string());
+            if sentence.chars().count() <= max_length
+                && runs.iter().all(|run| !run.contains(RUN_DELIMITER))
+            {
+                Some(sentence)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<Option<String>>>();
+    let mut reading_resps =
+        read_sentences(&sentences.iter().flatten().cloned().collect::<Vec<String>>())?.into_iter();
+    let mut readings = Vec::with_capacity(jp_runs.len());
+    let mut run_by_run = Vec::new();
+    for (batch, sentence) in batches.into_iter().zip(&sentences) {
+        let reading_resp = match sentence {
+            Some(_) => reading_resps.next().flatten(),
+            None => {
+                run_by_run.extend(batch.clone());
+                readings.extend(batch.map(|_| None));
+                continue;
+            }
+        };
+        let batch_readings = reading_resp.map(|reading_resp| {
+            reading_resp
+                .converted
+                .split(RUN_DELIMITER)
+                .map(|reading| clean_reading(reading, &reading_resp.output_type))
+                .collect::<Vec<String>>()
+        });
+        match batch_readings {
+            Some(batch_readings) if batch_readings.len() == batch.len() => {
+                readings.extend(batch_readings.into_iter().map(Some))
+            }
+            Some(batch_readings) => {
+                log::warn!(
+                    "got {} readings for {} runs, looking them up one by one",
+                    batch_readings.len(),
+                    batch.len()
+                );
+                run_by_run.extend(batch.clone());
+                readings.extend(batch.map(|_| None));
+            }
+            None => readings.extend(batch.map(|_| None)),
+        }
+    }
+    if run_by_run.is_empty() {
+        return Ok(readings);
+    }
+    // a run longer than the API accepts is read a sentence at a time and stitched back
+    let run_chunks = run_by_run
+        .iter()
+        .map(|&i| split_sentences(&jp_runs[i], max_length))
+        .collect::<Vec<Vec<String>>>();
+    let sentences = run_chunks
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<String>>();
+    let mut reading_resps = read_sentences(&sentences)?.into_iter();
+    for (i, chunks) in run_by_run.into_iter().zip(&run_chunks) {
+        let chunk_resps = reading_resps
+            .by_ref()
+            .take(chunks.len())
+            .collect::<Vec<Option<ReadingResp>>>();
+        readings[i] = chunk_resps
+            .into_iter()
+            .collect::<Option<Vec<ReadingResp>>>()
+            .map(|chunk_resps| stitch_readings(chunk_resps.into_iter()));
+    }
+    Ok(readings)
+}
+
+fn stitch_readings(reading_resps: impl Iterator<Item = ReadingResp>) -> String {
+    let mut reading = String::new();
+    for reading_resp in reading_resps {
+        if reading_resp.output_type == "roman" && !reading.is_empty() {
+            reading.push(' ');
+        }
+        reading.push_str(&clean_reading(
+            &reading_resp.converted,
+            &reading_resp.output_type,
+        ));
+    }
+    reading
+}
+
+// cut after the last sentence or clause end that fits, or at the limit when there is none
+fn split_sentences(run: &str, max_length: usize) -> Vec<String> {
+    let run_chars = run.chars().collect::<Vec<char>>();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while run_chars.len() - start > max_length {
+        let end = start + max_length;
+        let cut = run_chars[start..end]
+            .iter()
+            .rposition(|ch| ['。', '！', '？', '、'].contains(ch))
+            .map(|pos| start + pos + 1)
+            .unwrap_or(end);
+        chunks.push(run_chars[start..cut].iter().collect());
+        start = cut;
+    }
+    chunks.push(run_chars[start..].iter().collect());
+    chunks
+}
+
+// the API separates morphemes with spaces, which only romaji shows; kana readings
+// keep them as WORD_BREAK for kanji_ruby
+fn clean_reading(reading: &str, output_type: &str) -> String {
+    if output_type == "roman" {
+        reading.trim().to_string()
+    } else {
+        reading
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(&WORD_BREAK.to_string())
+    }
+}
+
+fn config_number(api_config: &Dictionary, key: &str, default: u64) -> u64 {
+    api_config
+        .get(key)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// host names come from the dictionary, a value that is not a host name falls back to the default
+fn config_host(api_config: &Dictionary, key: &str, default: &str) -> String {
+    checked_host(key, api_config.get(key), default)
+}
+
+fn checked_host(key: &str, host: Option<String>, default: &str) -> String {
+    match host {
+        Some(host) if is_host_name(&host) => host,
+        Some(host) => {
+            log::error!("invalid {} {:?}, using {}", key, host, default);
+            default.to_string()
+        }
+        None => default.to_string(),
+    }
+}
+
+fn is_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == ':' || c == '_')
+}
+
+// one sentence ready to be sent to the reading API
+struct ReadingCall {
+    provider: Box<dyn ReadingProvider>,
+    request_id: String,
+    req_body: String,
+    input_chars: usize,
+    timeout_ms: u64,
+    max_calls: u64,
+}
+
+#[derive(Debug)]
+struct ApiTimeout(u64);
+
+impl fmt::Display for ApiTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "reading API did not answer within {}ms", self.0)
+    }
+}
+
+impl std::error::Error for ApiTimeout {}
+
+// a 4xx, or a 5xx that retries did not get past
+#[derive(Debug)]
+struct ApiStatus(StatusCode);
+
+impl fmt::Display for ApiStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "reading API answered with status {}", self.0)
+    }
+}
+
+impl std::error::Error for ApiStatus {}
+
+// max_api_calls_per_request has been used up
+#[derive(Debug)]
+struct ApiCallLimit(u64);
+
+impl fmt::Display for ApiCallLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "reached the limit of {} reading API calls", self.0)
+    }
+}
+
+impl std::error::Error for ApiCallLimit {}
+
+fn reading_call(j: &str) -> Result<ReadingCall> {
+    let api_config = Dictionary::open("api_config");
+    let provider = provider::open_provider(&api_config)?;
+    // the API echoes request_id back, which makes retries traceable in the logs
+    let request_id = format!(
+        "add_ruby-{}-{}",
+        Utc::now().timestamp_millis(),
+        REQUEST_IDS.fetch_add(1, Ordering::Relaxed)
+    );
+    let req_body = provider.request_body(j, &request_id)?;
+
+    log::debug!(
+        "{} via {} with key {}",
+        &req_body,
+        provider.backend(),
+        provider.app_ids().index()
+    );
+
+    Ok(ReadingCall {
+        provider,
+        request_id,
+        req_body,
+        input_chars: j.chars().count(),
+        timeout_ms: config_number(&api_config, "api_timeout_ms", 2000),
+        max_calls: config_number(&api_config, "max_api_calls_per_request", 0),
+    })
+}
+
+// retries count as calls, 0 is no limit
+fn reading_request(reading_call: &ReadingCall) -> Result<Request<Body>> {
+    let calls = API_CALLS.fetch_add(1, Ordering::Relaxed);
+    if reading_call.max_calls > 0 && calls as u64 >= reading_call.max_calls {
+        API_CALLS.fetch_sub(1, Ordering::Relaxed);
+        return Err(ApiCallLimit(reading_call.max_calls).into());
+    }
+    reading_call.provider.request(&reading_call.req_body)
+}
+
+// the backend's own timeouts are longer than a page can wait for its readings
+fn wait_for_reading(
+    reading_call: &ReadingCall,
+    pending_req: Result<PendingRequest, SendError>,
+    sent: Instant,
+) -> Result<Response<Body>> {
+    let mut pending_req = pending_req?;
+    loop {
+        match pending_req.poll() {
+            PollResult::Done(resp) => {
+                let resp = resp?;
+                if log::log_enabled!(log::Level::Debug) {
+                    let timing = ApiTiming {
+                        request_id: &reading_call.request_id,
+                        input_chars: reading_call.input_chars,
+                        status: resp.status().as_u16(),
+                        api_ms: sent.elapsed().as_millis() as u64,
+                    };
+                    log::debug!("{}", serde_json::to_string(&timing)?);
+                }
+                return Ok(resp);
+            }
+            PollResult::Pending(pending) => pending_req = pending,
+        }
+        if sent.elapsed() >= Duration::from_millis(reading_call.timeout_ms) {
+            return Err(ApiTimeout(reading_call.timeout_ms).into());
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn parse_reading_resp(reading_call: &ReadingCall, resp: Response<Body>) -> Result<ReadingResp> {
+    let status = resp.status();
+    let body_bytes = resp.into_body().into_bytes();
+    let body_str = reading_body(&reading_call.request_id, status, &body_bytes)?;
+    reading_call
+        .provider
+        .parse(body_str, &reading_call.request_id)
+}
+
+fn reading_body<'a>(request_id: &str, status: StatusCode, body_bytes: &'a [u8]) -> Result<&'a str> {
+    let body_str = String::from_utf8_lossy(body_bytes);
+
+    log::debug!("{}", &body_str);
+
+    // an error page is not a reading, say what the API answered instead of failing in serde
+    if !status.is_success() {
+        log::error!(
+            "reading API request {} answered {}: {}",
+            request_id,
+            status,
+            body_str.chars().take(200).collect::<String>()
+        );
+        return Err(ApiStatus(status).into());
+    }
+    // a reading with replaced bytes would end up over the page as it is
+    std::str::from_utf8(body_bytes).map_err(|e| {
+        anyhow::anyhow!(
+            "reading API request {} answered invalid UTF-8: {}",
+            request_id,
+            e
+        )
+    })
+}
+
+// Send the sentences api_max_concurrency at a time and keep the readings in the
+// order of the sentences. A sentence whose first attempt fails is retried on its own.
+// The sentences left when max_api_calls_per_request is used up get None.
+fn get_reading_all(sentences: &[String]) -> Result<Vec<Option<ReadingResp>>> {
+    let api_config = Dictionary::open("api_config");
+    let max_concurrency = config_number(&api_config, "api_max_concurrency", 4).max(1) as usize;
+    let mut reading_resps = Vec::with_capacity(sentences.len());
+    let mut call_limit = None;
+    for group in sentences.chunks(max_concurrency) {
+        let mut pending_reqs = Vec::with_capacity(group.len());
+        for sentence in group {
+            let reading_call = reading_call(sentence)?;
+            let req = match reading_request(&reading_call) {
+                Ok(req) => req,
+                Err(e) if e.is::<ApiCallLimit>() => {
+                    call_limit = Some(e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let pending_req = req.send_async(reading_call.provider.backend());
+            pending_reqs.push((reading_call, Instant::now(), pending_req));
+        }
+        for (sentence, (reading_call, sent, pending_req)) in group.iter().zip(pending_reqs) {
+            let request_id = &reading_call.request_id;
+            let failure = match wait_for_reading(&reading_call, pending_req, sent) {
+                Ok(resp) if resp.status().is_server_error() || key_refused(resp.status()) => {
+                    format!("status {}", resp.status())
+                }
+                Ok(resp) => {
+                    reading_resps.push(Some(parse_reading_resp(&reading_call, resp)?));
+                    continue;
+                }
+                Err(e) if e.is::<ApiTimeout>() => {
+                    log::error!("reading API request {} timed out: {}", request_id, e);
+                    return Err(e);
+                }
+                Err(e) => e.to_string(),
+            };
+            log::warn!(
+                "reading API request {} failed ({}), retrying on its own",
+                request_id,
+                failure
+            );
+            match get_reading(sentence) {
+                Ok(reading_resp) => reading_resps.push(Some(reading_resp)),
+                Err(e) if e.is::<ApiCallLimit>() => {
+                    reading_resps.push(None);
+                    call_limit = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(ref e) = call_limit {
+            log::warn!(
+                "{}, {} sentences left without a reading",
+                e,
+                sentences.len() - reading_resps.iter().filter(|resp| resp.is_some()).count()
+            );
+            break;
+        }
+    }
+    reading_resps.resize_with(sentences.len(), || None);
+    Ok(reading_resps)
+}
+
+fn key_refused(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::FORBIDDEN
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NextStep {
+    // the same attempt again with the next key
+    NextKey,
+    Retry,
+    // a reading, or an answer whose status and body are logged as the error
+    Parse,
+    GiveUp,
+}
+
+// Retry network errors and 5xx, a 4xx will not get better and a timeout has already
+// used up the time the page can wait; the last 5xx is parsed like any other answer.
+// A key over its quota or refused does not count as an attempt, the next one is tried.
+fn next_step(
+    answer: Result<StatusCode, &anyhow::Error>,
+    keys_left: bool,
+    last_attempt: bool,
+) -> NextStep {
+    match answer {
+        Ok(status) if key_refused(status) && keys_left => NextStep::NextKey,
+        Ok(status) if status.is_server_error() && !last_attempt => NextStep::Retry,
+        Ok(_) => NextStep::Parse,
+        Err(e) if e.is::<ApiTimeout>() || last_attempt => NextStep::GiveUp,
+        Err(_) => NextStep::Retry,
+    }
+}
+
+fn get_reading(j: &str) -> Result<ReadingResp> {
+    let api_config = Dictionary::open("api_config");
+    let max_attempts = config_number(&api_config, "api_max_attempts", 3);
+    let retry_delay_ms = config_number(&api_config, "api_retry_delay_ms", 100);
+    let mut reading_call = reading_call(j)?;
+    let request_id = reading_call.request_id.clone();
+
+    let mut attempt = 1;
+    let mut keys_tried = 1;
+    let mut waited_ms = 0;
+    let resp = loop {
+        let req = reading_request(&reading_call)?;
+
+        let delay_ms = retry_delay_ms.saturating_mul(1 << (attempt - 1).min(16));
+        let last_attempt = attempt >= max_attempts || waited_ms + delay_ms > MAX_RETRY_DELAY_MS;
+        let sent = Instant::now();
+        let pending_req = req.send_async(reading_call.provider.backend());
+        let answer = wait_for_reading(&reading_call, pending_req, sent);
+        let keys_left = keys_tried < reading_call.provider.app_ids().len();
+        let step = next_step(
+            answer.as_ref().map(|resp| resp.status()),
+            keys_left,
+            last_attempt,
+        );
+        let failure = match (step, answer) {
+            (NextStep::NextKey, Ok(resp)) => {
+                let refused = reading_call.provider.app_ids().index();
+                reading_call.provider.app_ids_mut().next();
+                reading_call.req_body = reading_call.provider.request_body(j, &request_id)?;
+                log::warn!(
+                    "reading API request {} key {} answered {}, switching to key {}",
+                    request_id,
+                    refused,
+                    resp.status(),
+                    reading_call.provider.app_ids().index()
+                );
+                keys_tried += 1;
+                continue;
+            }
+            (NextStep::Retry, Ok(resp)) => format!("status {}", resp.status()),
+            (NextStep::Retry, Err(e)) => e.to_string(),
+            (_, Ok(resp)) => break resp,
+            (_, Err(e)) if e.is::<ApiTimeout>() => {
+                log::error!("reading API request {} timed out: {}", request_id, e);
+                return Err(e);
+            }
+            (_, Err(e)) => {
+                return Err(anyhow::anyhow!(
+                    "reading API request {} failed after {} attempts: {}",
+                    request_id,
+                    attempt,
+                    e
+                ));
+            }
+        };
+        log::warn!(
+            "reading API request {} attempt {} failed ({}), retrying in {}ms",
+            request_id,
+            attempt,
+            failure,
+            delay_ms
+        );
+        thread::sleep(Duration::from_millis(delay_ms));
+        waited_ms += delay_ms;
+        attempt += 1;
+    };
+
+    parse_reading_resp(&reading_call, resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use add_ruby::html::analyze_jp;
+    use encoding_rs::{SHIFT_JIS, UTF_16LE};
+    use std::cell::RefCell;
+
+    const PAGE: &str = "<!DOCTYPE html><html><head><title>t</title></head><body><p>東京と大阪</p><p>京都</p><p>日<b>本</b>語の本</p></body></html>";
+
+    fn run_filter() -> RunFilter {
+        RunFilter {
+            skip_katakana: false,
+            kana_ruby: false,
+            min_kanji: 1,
+            jlpt_max: None,
+            title_ruby: false,
+            first_occurrence_only: false,
+            attach_latin: false,
+            max_runs: 10000,
+            annotated: HashSet::new(),
+            runs: 0,
+            dropped: 0,
+        }
+    }
+
+    fn page_stream(max_length: usize) -> PageStream {
+        PageStream {
+            body: None,
+            content_encoding: None,
+            encoding: UTF_8,
+            analyzer: StreamAnalyzer::new(false, &[]),
+            run_filter: run_filter(),
+            ruby_template: ruby_template("(", ")", false),
+            kanji_ruby: KanjiRuby::Compound,
+            romaji: false,
+            merge_inline_runs: true,
+            head_markup: Some("<style>rt{}</style>".to_string()),
+            held: String::new(),
+            window: Window::default(),
+            output_encoding: UTF_8,
+            output_coding: None,
+            max_length,
+            metrics: TransformMetrics::default(),
+        }
+    }
+
+    fn buffered_page(html: &str) -> String {
+        let (mut html_parts, mut jp_runs) = analyze_jp(html, false, &[]);
+        run_filter().skip(&mut html_parts, &mut jp_runs);
+        let groups = inline_groups(html, &html_parts);
+        let buffered = generate_html_with_ruby(
+            html,
+            &html_parts,
+            &jp_runs,
+            &ruby_template("(", ")", false),
+            KanjiRuby::Compound,
+            false,
+            |jp_runs| grouped_fake_readings(jp_runs, &groups),
+        )
+        .unwrap();
+        insert_in_head(&buffered, "<style>rt{}</style>")
+    }
+
+    fn fake_readings(jp_runs: &[String]) -> Result<Vec<Option<String>>> {
+        Ok(jp_runs
+            .iter()
+            .map(|run| Some(run.chars().map(|_| 'よ').collect()))
+            .collect())
+    }
+
+    // the runs of a group read as one text, as get_grouped_readings reads them
+    fn grouped_fake_readings(jp_runs: &[String], groups: &[usize]) -> Result<Vec<Option<String>>> {
+        assert_eq!(jp_runs.len(), groups.len());
+        fake_readings(jp_runs)
+    }
+
+    // a body that arrives in chunks, as from a backend that sends it chunked
+    struct ChunkedBody<'a> {
+        chunks: Vec<Vec<u8>>,
+        events: &'a RefCell<Vec<String>>,
+    }
+
+    impl<'a> Read for ChunkedBody<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.chunks.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.chunks.remove(0);
+            self.events
+                .borrow_mut()
+                .push(format!("read {}", String::from_utf8_lossy(&chunk)));
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn chunked<'a>(
+        body: &[u8],
+        chunk_len: usize,
+        events: &'a RefCell<Vec<String>>,
+    ) -> ChunkedBody<'a> {
+        ChunkedBody {
+            chunks: body.chunks(chunk_len).map(|chunk| chunk.to_vec()).collect(),
+            events,
+        }
+    }
+
+    struct EventWriter<'a>(&'a RefCell<Vec<String>>, Vec<u8>);
+
+    impl<'a> Write for EventWriter<'a> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0
+                .borrow_mut()
+                .push(format!("write {}", String::from_utf8_lossy(buf)));
+            self.1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn streamed_page_matches_buffered_page() {
+        for chunk_len in 1..PAGE.len() + 1 {
+            let events = RefCell::new(Vec::new());
+            let mut body = Vec::new();
+            page_stream(4)
+                .transform(
+                    chunked(PAGE.as_bytes(), chunk_len, &events),
+                    &mut body,
+                    grouped_fake_readings,
+                )
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(body).unwrap(),
+                buffered_page(PAGE),
+                "chunks of {}",
+                chunk_len
+            );
+        }
+    }
+
+    #[test]
+    fn streamed_page_is_sent_window_by_window() {
+        let events = RefCell::new(Vec::new());
+        let mut body = EventWriter(&events, Vec::new());
+        page_stream(4)
+            .transform(PAGE.as_bytes(), &mut body, |jp_runs, groups| {
+                events
+                    .borrow_mut()
+                    .push(format!("lookup {}", jp_runs.join(",")));
+                grouped_fake_readings(jp_runs, groups)
+            })
+            .unwrap();
+        // the page up to the first run goes out before any lookup, every later window
+        // right after its own; 日<b>本</b>語の本 is looked up as one group
+        let events = events.into_inner();
+        let first_lookup = events
+            .iter()
+            .position(|event| event.starts_with("lookup"))
+            .unwrap();
+        assert_eq!(
+            events[..first_lookup].concat(),
+            "write <!DOCTYPE html><html><head><style>rt{}</style><title>t</title></head><body><p>"
+        );
+        let lookups = events
+            .iter()
+            .filter(|event| event.starts_with("lookup"))
+            .collect::<Vec<&String>>();
+        assert_eq!(
+            lookups,
+            vec!["lookup 東京と大阪", "lookup 京都", "lookup 日,本,語の本"]
+        );
+        assert!(events[first_lookup..]
+            .windows(2)
+            .all(|pair| pair[0].starts_with("write") != pair[1].starts_with("write")));
+    }
+
+    #[test]
+    fn leading_html_goes_out_while_the_rest_is_read() {
+        let chunks = [
+            "<!DOCTYPE html><html><head><title>t</title>",
+            "</head><body><p>Intro</p>",
+            "<p>東京と大阪</p>",
+            "</body></html>",
+        ];
+        let events = RefCell::new(Vec::new());
+        let body = ChunkedBody {
+            chunks: chunks
+                .iter()
+                .map(|chunk| chunk.as_bytes().to_vec())
+                .collect(),
+            events: &events,
+        };
+        let mut out = EventWriter(&events, Vec::new());
+        page_stream(1000)
+            .transform(body, &mut out, |jp_runs, groups| {
+                events.borrow_mut().push("lookup".to_string());
+                grouped_fake_readings(jp_runs, groups)
+            })
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out.1).unwrap(),
+            buffered_page(&chunks.concat())
+        );
+        let events = events.into_inner();
+        let position = |event: &str| events.iter().position(|e| e.as_str() == event).unwrap();
+        // the head goes out before the second chunk is read and the text before the
+        // first run before the third, the lookup waits for the end of its window
+        assert_eq!(
+            &events[..2],
+            [
+                "read <!DOCTYPE html><html><head><title>t</title>",
+                "write <!DOCTYPE html><html><head><style>rt{}</style><title>t",
+            ]
+        );
+        assert!(
+            position("write </title></head><body><p>Intro") < position("read <p>東京と大阪</p>")
+        );
+        assert!(position("read </body></html>") < position("lookup"));
+    }
+
+    #[test]
+    fn streamed_page_continues_as_it_is_after_a_failed_lookup() {
+        let mut stream = page_stream(4);
+        let mut body = Vec::new();
+        let mut lookups = 0;
+        stream
+            .transform(PAGE.as_bytes(), &mut body, |jp_runs, _| {
+                lookups += 1;
+                if lookups == 2 {
+                    Err(anyhow::anyhow!("API down"))
+                } else {
+                    fake_readings(jp_runs)
+                }
+            })
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert_eq!(lookups, 2);
+        assert_eq!(stream.metrics.passthrough, Some("reading API"));
+        assert!(body.contains("<ruby>東京と大阪<rp>(</rp><rt>よよよよよ</rt><rp>)</rp></ruby>"));
+        assert!(body.ends_with("<p>京都</p><p>日<b>本</b>語の本</p></body></html>"));
+    }
+
+    #[test]
+    fn english_pages_make_no_lookup() {
+        let html = "<html><head><title>t</title></head><body><p>Hello, world.</p></body></html>";
+        let no_lookup = |_: &[String]| -> Result<Vec<Option<String>>> {
+            panic!("a page without Japanese was looked up")
+        };
+        let (html_parts, jp_runs) = analyze_jp(html, false, &[]);
+        let buffered = generate_html_with_ruby(
+            html,
+            &html_parts,
+            &jp_runs,
+            &ruby_template("(", ")", false),
+            KanjiRuby::Compound,
+            false,
+            no_lookup,
+        )
+        .unwrap();
+        assert_eq!(buffered, html);
+        let mut body = Vec::new();
+        page_stream(1000)
+            .transform(html.as_bytes(), &mut body, |jp_runs, _| no_lookup(jp_runs))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            insert_in_head(html, "<style>rt{}</style>")
+        );
+    }
+
+    #[test]
+    fn page_without_head_gets_markup_at_its_start() {
+        let mut body = Vec::new();
+        page_stream(1000)
+            .transform("<p>東京</p>".as_bytes(), &mut body, grouped_fake_readings)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "<style>rt{}</style><p><ruby>東京<rp>(</rp><rt>よよ</rt><rp>)</rp></ruby></p>"
+        );
+        let mut body = Vec::new();
+        page_stream(1000)
+            .transform(
+                "<html><p>abc</p></html>".as_bytes(),
+                &mut body,
+                grouped_fake_readings,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "<html><style>rt{}</style><p>abc</p></html>"
+        );
+    }
+
+    #[test]
+    fn compressed_shift_jis_pages_are_decoded_as_they_are_read() {
+        let page = "<html><head><meta charset=\"shift_jis\"></head><body><p>漢字と<b>仮名</b></p></body></html>";
+        let encoded = SHIFT_JIS.encode(page).0.into_owned();
+        let compressed = compression::compress("gzip", &encoded).unwrap();
+        let expected = SHIFT_JIS.encode(&buffered_page(page)).0.into_owned();
+        for chunk_len in 1..compressed.len() + 1 {
+            let mut stream = page_stream(1000);
+            stream.content_encoding = Some("gzip".to_string());
+            stream.encoding = SHIFT_JIS;
+            stream.output_encoding = SHIFT_JIS;
+            let events = RefCell::new(Vec::new());
+            let mut body = Vec::new();
+            stream
+                .transform(
+                    chunked(&compressed, chunk_len, &events),
+                    &mut body,
+                    grouped_fake_readings,
+                )
+                .unwrap();
+            assert_eq!(body, expected, "chunks of {}", chunk_len);
+        }
+    }
+
+    #[test]
+    fn runs_with_fewer_kanji_than_min_kanji_are_left_out() {
+        let html = "<p>本</p><p>日本</p><p>食べる</p><p>日本語の本</p><p>ひらがな</p>";
+        let kept = |min_kanji| {
+            let (mut html_parts, mut jp_runs) = analyze_jp(html, false, &[]);
+            let mut run_filter = run_filter();
+            run_filter.min_kanji = min_kanji;
+            run_filter.skip(&mut html_parts, &mut jp_runs);
+            jp_runs
+        };
+        assert_eq!(kept(1), runs(&["本", "日本", "食べる", "日本語の本"]));
+        assert_eq!(kept(2), runs(&["日本", "日本語の本"]));
+    }
+
+    #[test]
+    fn a_repeated_word_gets_ruby_once() {
+        let html = "<title>東京</title><p>東京</p><p>大阪</p><p>東京</p><p><b>東京</b></p>";
+        let (mut html_parts, mut jp_runs) = analyze_jp(html, false, &[]);
+        let mut run_filter = run_filter();
+        run_filter.title_ruby = true;
+        run_filter.first_occurrence_only = true;
+        run_filter.skip(&mut html_parts, &mut jp_runs);
+        run_filter.limit(html, &mut html_parts, &mut jp_runs);
+        let page = generate_html_with_ruby(
+            html,
+            &html_parts,
+            &jp_runs,
+            &ruby_template("(", ")", false),
+            KanjiRuby::Compound,
+            false,
+            fake_readings,
+        )
+        .unwrap();
+        // the title is annotated apart from the body
+        assert_eq!(
+            page,
+            "<title>東京(よよ)</title><p><ruby>東京<rp>(</rp><rt>よよ</rt><rp>)</rp></ruby></p><p><ruby>大阪<rp>(</rp><rt>よよ</rt><rp>)</rp></ruby></p><p>東京</p><p><b>東京</b></p>"
+        );
+    }
+
+    #[test]
+    fn max_runs_and_first_occurrence_carry_over_between_pieces() {
+        let mut stream = page_stream(1000);
+        stream.run_filter.first_occurrence_only = true;
+        stream.run_filter.max_runs = 2;
+        let page = "<p>東京</p><p>東京</p><p>大阪</p><p>京都</p>";
+        let events = RefCell::new(Vec::new());
+        let mut body = Vec::new();
+        stream
+            .transform(
+                chunked(page.as_bytes(), 7, &events),
+                &mut body,
+                grouped_fake_readings,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "<style>rt{}</style><p><ruby>東京<rp>(</rp><rt>よよ</rt><rp>)</rp></ruby></p><p>東京</p>\
+             <p><ruby>大阪<rp>(</rp><rt>よよ</rt><rp>)</rp></ruby></p><p>京都</p>"
+        );
+        assert_eq!(stream.metrics.runs, 2);
+        assert_eq!(stream.run_filter.dropped, 1);
+    }
+
+    #[test]
+    fn utf16_pages_are_written_as_utf8() {
+        let body = UTF_16LE.encode("<p>漢字</p>").0.into_owned();
+        let encoding = detect_encoding(Some("text/html; charset=utf-16le"), &body);
+        assert_eq!(encoding, Some(UTF_16LE));
+        assert_eq!(UTF_16LE.output_encoding(), UTF_8);
+        assert_eq!(
+            with_charset("text/html; charset=\"utf-16le\"", UTF_8.name()),
+            "text/html; charset=UTF-8"
+        );
+        assert_eq!(
+            with_charset("text/html;charset=UTF-16; foo=bar", UTF_8.name()),
+            "text/html; foo=bar; charset=UTF-8"
+        );
+        assert_eq!(
+            with_charset("text/html", UTF_8.name()),
+            "text/html; charset=UTF-8"
+        );
+    }
+
+    fn runs(runs: &[&str]) -> Vec<String> {
+        runs.iter().map(|run| run.to_string()).collect()
+    }
+
+    fn reading_resp(converted: &str) -> ReadingResp {
+        ReadingResp {
+            converted: converted.to_string(),
+            output_type: "hiragana".to_string(),
+            request_id: String::new(),
+        }
+    }
+
+    #[test]
+    fn readings_of_every_output_type_are_cleaned() {
+        let stitched = |output_type: &str, converted: &[&str]| {
+            stitch_readings(converted.iter().map(|converted| ReadingResp {
+                converted: converted.to_string(),
+                output_type: output_type.to_string(),
+                request_id: String::new(),
+            }))
+        };
+        assert_eq!(
+            stitched("hiragana", &["とうきょう と ", "おおさか"]),
+            "とうきょう\u{200b}とおおさか"
+        );
+        assert_eq!(
+            stitched("katakana", &[" トウキョウ ト"]),
+            "トウキョウ\u{200b}ト"
+        );
+        assert_eq!(
+            stitched("roman", &["toukyou to ", " oosaka"]),
+            "toukyou to oosaka"
+        );
+        // the local dictionary has no romaji, those runs go to the API
+        assert_eq!(local_reading("日本", "hiragana").as_deref(), Some("にほん"));
+        assert_eq!(local_reading("日本", "katakana").as_deref(), Some("ニホン"));
+        assert_eq!(local_reading("日本", "roman"), None);
+    }
+
+    #[test]
+    fn counters_are_left_to_the_api() {
+        let html = "<p>3人の友人</p><p>5月1日</p><p>2024年</p>";
+        let (_, jp_runs) = analyze_jp(html, false, &[]);
+        assert_eq!(jp_runs, runs(&["人の友人", "月", "日", "年"]));
+        for run in &jp_runs {
+            assert_eq!(local_reading(run, "hiragana"), None, "{}", run);
+        }
+    }
+
+    #[test]
+    fn identical_runs_are_looked_up_once() {
+        // the half-width カメラ folds to the same word
+        let jp_runs = runs(&["東京", "日本", "カメラ", "東京", "大阪", "ｶﾒﾗ", "日本"]);
+        let local = |run: &str| match run {
+            "日本" => Some("にほん".to_string()),
+            _ => None,
+        };
+        let mut calls = Vec::new();
+        let readings = read_runs(&jp_runs, &local, &mut |runs: &[String]| {
+            calls.push(runs.to_vec());
+            Ok(runs.iter().map(|run| Some(format!("<{}>", run))).collect())
+        })
+        .unwrap();
+        assert_eq!(calls, vec![runs(&["東京", "カメラ", "大阪"])]);
+        assert_eq!(
+            readings,
+            vec![
+                Some("<東京>".to_string()),
+                Some("にほん".to_string()),
+                Some("<カメラ>".to_string()),
+                Some("<東京>".to_string()),
+                Some("<大阪>".to_string()),
+                Some("<カメラ>".to_string()),
+                Some("にほん".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn batches_are_read_together_and_in_order() {
+        let jp_runs = runs(&["東京", "大阪", "京都", "日本語"]);
+        let mut calls = Vec::new();
+        let readings = read_batches(&jp_runs, 6, |sentences| {
+            calls.push(sentences.to_vec());
+            Ok(sentences
+                .iter()
+                .map(|sentence| Some(reading_resp(&sentence.replace("京", "きょう"))))
+                .collect())
+        })
+        .unwrap();
+        assert_eq!(calls, vec![runs(&["東京,大阪", "京都", "日本語"])]);
+        assert_eq!(
+            readings,
+            vec![
+                Some("東きょう".to_string()),
+                Some("大阪".to_string()),
+                Some("きょう都".to_string()),
+                Some("日本語".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unmatched_and_long_runs_are_read_run_by_run() {
+        let jp_runs = runs(&["東京", "大阪", "あいうえお。かきくけこ", "京都"]);
+        let mut calls = Vec::new();
+        let readings = read_batches(&jp_runs, 6, |sentences| {
+            calls.push(sentences.to_vec());
+            Ok(sentences
+                .iter()
+                .map(|sentence| match sentence.as_str() {
+                    // the API split the batch into three readings
+                    "東京,大阪" => Some(reading_resp("とう,きょう,おおさか")),
+                    _ => Some(reading_resp(sentence)),
+                })
+                .collect())
+        })
+        .unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                runs(&["東京,大阪", "京都"]),
+                runs(&["東京", "大阪", "あいうえお。", "かきくけこ"]),
+            ]
+        );
+        assert_eq!(
+            readings,
+            vec![
+                Some("東京".to_string()),
+                Some("大阪".to_string()),
+                Some("あいうえお。かきくけこ".to_string()),
+                Some("京都".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_with_a_comma_are_read_run_by_run() {
+        let jp_runs = runs(&["京都", "東京,大阪", "奈良"]);
+        let mut calls = Vec::new();
+        let readings = read_batches(&jp_runs, 100, |sentences| {
+            calls.push(sentences.to_vec());
+            Ok(sentences
+                .iter()
+                .map(|sentence| Some(reading_resp(&format!("[{}]", sentence))))
+                .collect())
+        })
+        .unwrap();
+        assert_eq!(
+            calls,
+            vec![Vec::new(), runs(&["京都", "東京,大阪", "奈良"])]
+        );
+        assert_eq!(
+            readings,
+            vec![
+                Some("[京都]".to_string()),
+                Some("[東京,大阪]".to_string()),
+                Some("[奈良]".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_past_the_call_limit_get_no_reading() {
+        let jp_runs = runs(&["東京", "大阪", "京都"]);
+        let readings = read_batches(&jp_runs, 3, |sentences| {
+            Ok(sentences
+                .iter()
+                .enumerate()
+                .map(|(i, sentence)| {
+                    if i < 2 {
+                        Some(reading_resp(sentence))
+                    } else {
+                        None
+                    }
+                })
+                .collect())
+        })
+        .unwrap();
+        assert_eq!(
+            readings,
+            vec![Some("東京".to_string()), Some("大阪".to_string()), None]
+        );
+    }
+
+    #[test]
+    fn vary_is_merged_into_the_backends() {
+        let mut resp = Response::builder()
+            .header(VARY, "Origin, accept-encoding")
+            .body(())
+            .unwrap();
+        add_vary(&mut resp, &["Cookie", "Accept-Encoding"]);
+        assert_eq!(resp.headers()[VARY], "Origin, accept-encoding, Cookie");
+
+        let mut resp = Response::builder().header(VARY, "*").body(()).unwrap();
+        add_vary(&mut resp, &["Accept-Encoding"]);
+        assert_eq!(resp.headers()[VARY], "*");
+
+        let mut resp = Response::new(());
+        add_vary(&mut resp, &["Accept-Encoding"]);
+        assert_eq!(resp.headers()[VARY], "Accept-Encoding");
+    }
+
+    #[test]
+    fn passed_through_pages_get_a_weak_etag() {
+        assert_eq!(weak_etag("\"abc\""), "W/\"abc\"");
+        assert_eq!(weak_etag("W/\"abc\""), "W/\"abc\"");
+        assert_eq!(ruby_etag("W/\"abc\"", "1f"), "W/\"abc-ruby1f\"");
+    }
+
+    #[test]
+    fn preserve_host_sends_the_clients_host() {
+        let req = |host: &str| Request::builder().header(HOST, host).body(()).unwrap();
+        let backend = || "origin.example.com".to_string();
+        assert_eq!(
+            request_host(&req("www.example.jp"), backend(), true),
+            "www.example.jp"
+        );
+        assert_eq!(
+            request_host(&req("www.example.jp"), backend(), false),
+            "origin.example.com"
+        );
+        assert_eq!(
+            request_host(&req("bad host/"), backend(), true),
+            "origin.example.com"
+        );
+        assert_eq!(
+            request_host(&Request::new(()), backend(), true),
+            "origin.example.com"
+        );
+    }
+
+    #[test]
+    fn only_etags_of_current_transforms_are_revalidated() {
+        let mut req = Request::builder()
+            .header(IF_NONE_MATCH, "W/\"abc-ruby1f\", \"def\", W/\"ghi-ruby2e\"")
+            .body(())
+            .unwrap();
+        backend_if_none_match(&mut req, "1f").unwrap();
+        assert_eq!(req.headers()[IF_NONE_MATCH], "W/\"abc\"");
+
+        let mut req = Request::builder()
+            .header(IF_NONE_MATCH, "W/\"abc-ruby2e\", \"def\"")
+            .body(())
+            .unwrap();
+        backend_if_none_match(&mut req, "1f").unwrap();
+        assert!(req.headers().get(IF_NONE_MATCH).is_none());
+
+        let mut req = Request::builder()
+            .header(IF_NONE_MATCH, "*")
+            .body(())
+            .unwrap();
+        backend_if_none_match(&mut req, "1f").unwrap();
+        assert_eq!(req.headers()[IF_NONE_MATCH], "*");
+    }
+
+    #[test]
+    fn paths_match_prefixes_and_regexes() {
+        let patterns = "/news/, ~/[0-9]+\\.html";
+        assert!(path_matches(patterns, "/news/today.html"));
+        assert!(path_matches(patterns, "/2024.html"));
+        assert!(!path_matches(patterns, "/about/2024.html"));
+        assert!(!path_matches(patterns, "/new"));
+        assert!(!path_matches("~/[", "/["));
+        assert!(!path_matches("", "/"));
+    }
+
+    #[test]
+    fn japanese_is_preferred_unless_another_language_comes_first() {
+        let accepting = |accept_language: &str| {
+            let req = Request::builder()
+                .header(ACCEPT_LANGUAGE, accept_language)
+                .body(())
+                .unwrap();
+            prefers_japanese(&req)
+        };
+        assert!(!accepting("en"));
+        assert!(!accepting("ja;q=0"));
+        assert!(!accepting("ja;q=0, *"));
+        assert!(accepting("ja-JP"));
+        assert!(accepting("en;q=0.5, ja-JP"));
+        assert!(prefers_japanese(&Request::new(())));
+    }
+
+    #[test]
+    fn redirect_cookies_join_the_clients() {
+        let set_cookies = [
+            HeaderValue::from_static("session=new; Path=/; HttpOnly"),
+            HeaderValue::from_static("lang=ja"),
+            HeaderValue::from_static("tracking=; Max-Age=0"),
+        ];
+        assert_eq!(
+            merge_cookies(
+                Some("session=old; tracking=1; ruby_pref=on"),
+                set_cookies.iter()
+            ),
+            Some("ruby_pref=on; session=new; lang=ja".to_string())
+        );
+        assert_eq!(
+            merge_cookies(None, set_cookies[..1].iter()),
+            Some("session=new".to_string())
+        );
+        assert_eq!(
+            merge_cookies(Some("tracking=1"), set_cookies[2..].iter()),
+            None
+        );
+        assert_eq!(
+            merge_cookies(Some("a=1"), [].iter()),
+            Some("a=1".to_string())
+        );
+    }
+
+    #[test]
+    fn kanji_levels_run_from_n5_to_n1() {
+        assert_eq!(kanji_level('日'), 5);
+        assert_eq!(kanji_level('漢'), 4);
+        assert_eq!(kanji_level('政'), 3);
+        assert_eq!(kanji_level('党'), 2);
+        assert_eq!(kanji_level('憂'), 1);
+        // not a joyo kanji
+        assert_eq!(kanji_level('嘘'), 0);
+        assert_eq!(jlpt_level("N1"), Some(1));
+        assert_eq!(jlpt_level(" n5 "), Some(5));
+        assert_eq!(jlpt_level("N6"), None);
+    }
+
+    #[test]
+    fn runs_a_reader_knows_are_left_out() {
+        // an N5 reader
+        assert!(knows_kanji("日本", 5));
+        assert!(!knows_kanji("日本語の政治", 5));
+        assert!(!knows_kanji("憂鬱", 5));
+        // an N1 reader
+        assert!(knows_kanji("日本語の政治", 1));
+        assert!(knows_kanji("憂鬱", 1));
+        assert!(!knows_kanji("嘘", 1));
+        // no kanji, nothing to know
+        assert!(!knows_kanji("ひらがな", 5));
+        assert!(!knows_kanji("カタカナ", 1));
+    }
+
+    #[test]
+    fn content_length_follows_the_new_body() {
+        let html = "<p><ruby>日本<rt>にほん</rt></ruby></p>";
+        for output_coding in [None, Some("gzip"), Some("br")].iter() {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_LENGTH, HeaderValue::from_static("12"));
+            let body = encode_body(&mut headers, html, UTF_8, *output_coding).unwrap();
+            assert_eq!(headers[CONTENT_LENGTH], body.len().to_string().as_str());
+            let decoded = compression::decompress(*output_coding, &body, html.len()).unwrap();
+            assert_eq!(decoded, html.as_bytes());
+        }
+        let mut headers = HeaderMap::new();
+        let body = encode_body(&mut headers, html, SHIFT_JIS, None).unwrap();
+        // the five Japanese characters take two bytes each instead of three
+        assert_eq!(body.len(), html.len() - 5);
+        assert_eq!(headers[CONTENT_LENGTH], body.len().to_string().as_str());
+    }
+
+    #[test]
+    fn shift_jis_labelled_utf8_is_passed_through_as_it_came() {
+        let raw_body = SHIFT_JIS
+            .encode("<p>日本語のページです。漢字が読めます。</p>")
+            .0
+            .into_owned();
+        match read_page(
+            Some("text/html; charset=utf-8"),
+            None,
+            raw_body.clone(),
+            1_000_000,
+            0,
+        ) {
+            PageBody::Passthrough(reason, body) => {
+                assert_eq!(reason, "charset");
+                assert_eq!(body, raw_body);
+            }
+            PageBody::Decoded(..) => panic!("Shift_JIS was decoded as UTF-8"),
+        }
+        match read_page(
+            Some("text/html; charset=shift_jis"),
+            None,
+            raw_body,
+            1_000_000,
+            0,
+        ) {
+            PageBody::Decoded(body, encoding) => {
+                assert_eq!(encoding, SHIFT_JIS);
+                assert!(body.starts_with("<p>日本語"));
+            }
+            PageBody::Passthrough(reason, _) => panic!("passed through: {}", reason),
+        }
+    }
+
+    #[test]
+    fn pages_under_min_japanese_percent_are_passed_through() {
+        let html = format!("<p>{}日</p>", "a".repeat(99));
+        let read = |min_japanese_percent| match read_page(
+            Some("text/html; charset=utf-8"),
+            None,
+            html.clone().into_bytes(),
+            1_000_000,
+            min_japanese_percent,
+        ) {
+            PageBody::Decoded(..) => "decoded",
+            PageBody::Passthrough(reason, _) => reason,
+        };
+        assert_eq!(read(1), "decoded");
+        assert_eq!(read(2), "not japanese");
+    }
+
+    #[test]
+    fn reading_answers_that_are_not_utf8_are_errors() {
+        let body = SHIFT_JIS
+            .encode(r#"{"converted":"にほんご"}"#)
+            .0
+            .into_owned();
+        let e = reading_body("r1", StatusCode::OK, &body).unwrap_err();
+        assert!(e.to_string().contains("invalid UTF-8"), "{}", e);
+        let e = reading_body("r1", StatusCode::INTERNAL_SERVER_ERROR, b"{}").unwrap_err();
+        assert!(e.is::<ApiStatus>());
+        assert_eq!(
+            reading_body("r1", StatusCode::OK, "{}".as_bytes()).unwrap(),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn refused_keys_move_on_to_the_next_key() {
+        for status in [
+            StatusCode::UNAUTHORIZED,
+            StatusCode::FORBIDDEN,
+            StatusCode::TOO_MANY_REQUESTS,
+        ]
+        .iter()
+        {
+            assert_eq!(next_step(Ok(*status), true, false), NextStep::NextKey);
+            // even on the last attempt, a key change is not an attempt
+            assert_eq!(next_step(Ok(*status), true, true), NextStep::NextKey);
+            assert_eq!(next_step(Ok(*status), false, false), NextStep::Parse);
+        }
+        assert_eq!(
+            next_step(Ok(StatusCode::INTERNAL_SERVER_ERROR), true, false),
+            NextStep::Retry
+        );
+    }
+
+    #[test]
+    fn reading_api_errors_are_retried_or_reported() {
+        let too_many = Ok(StatusCode::TOO_MANY_REQUESTS);
+        let server_error = Ok(StatusCode::INTERNAL_SERVER_ERROR);
+        // a 429 with no other key to try is not retried, its status is the error
+        assert_eq!(next_step(too_many, false, false), NextStep::Parse);
+        let e =
+            reading_body("r1", StatusCode::TOO_MANY_REQUESTS, b"<html>busy</html>").unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<ApiStatus>().map(|status| status.0),
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+        // a 500 is tried again until the last attempt, which reports it
+        assert_eq!(next_step(server_error, false, false), NextStep::Retry);
+        assert_eq!(next_step(server_error, false, true), NextStep::Parse);
+        let e = reading_body("r1", StatusCode::INTERNAL_SERVER_ERROR, b"").unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<ApiStatus>().map(|status| status.0),
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+        assert_eq!(
+            next_step(Ok(StatusCode::BAD_REQUEST), false, false),
+            NextStep::Parse
+        );
+        let network = anyhow::anyhow!("connection reset");
+        assert_eq!(next_step(Err(&network), false, false), NextStep::Retry);
+        assert_eq!(next_step(Err(&network), false, true), NextStep::GiveUp);
+        let timeout = anyhow::Error::new(ApiTimeout(100));
+        assert_eq!(next_step(Err(&timeout), true, false), NextStep::GiveUp);
+    }
+
+    #[test]
+    fn head_requests_are_passed_through() {
+        let resp = Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=UTF-8")
+            .header(CONTENT_LENGTH, "1234")
+            .body(())
+            .unwrap();
+        assert_eq!(passthrough_reason(true, &Method::HEAD, &resp), Some("head"));
+        assert_eq!(passthrough_reason(true, &Method::GET, &resp), None);
+        assert_eq!(
+            passthrough_reason(false, &Method::GET, &resp),
+            Some("ruby off")
+        );
+        let resp = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(CACHE_CONTROL, "max-age=60, No-Transform")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            passthrough_reason(true, &Method::GET, &resp),
+            Some("no-transform")
+        );
+        let resp = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header(CONTENT_TYPE, "text/html")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            passthrough_reason(true, &Method::GET, &resp),
+            Some("not html")
+        );
+    }
 }
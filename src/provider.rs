@@ -0,0 +1,389 @@
+use crate::{checked_host, ReadingResp, API_BACKEND, DEFAULT_OUTPUT_TYPE, OUTPUT_TYPES};
+use add_ruby::ruby::to_katakana;
+use anyhow::Result;
+use chrono::Utc;
+use fastly::http::{header, Method};
+use fastly::{dictionary::Dictionary, Body, Request};
+use serde::{Deserialize, Serialize};
+
+const YAHOO_BACKEND: &str = "jlp.yahooapis.jp";
+
+// A reading API. Readings come back in output_type whichever API answers them,
+// with spaces between words like the goo API does.
+pub(crate) trait ReadingProvider {
+    fn backend(&self) -> &str;
+    fn request_body(&self, sentence: &str, request_id: &str) -> Result<String>;
+    fn request(&self, req_body: &str) -> Result<Request<Body>>;
+    fn parse(&self, resp_body: &str, request_id: &str) -> Result<ReadingResp>;
+    fn app_ids(&self) -> &AppIds;
+    fn app_ids_mut(&mut self) -> &mut AppIds;
+}
+
+// The application ids in api_id, comma separated to spread the requests over the
+// quota of several keys. Requests can not share a counter, each reading request
+// starts at a key of its own and moves on when that key is refused.
+pub(crate) struct AppIds {
+    ids: Vec<String>,
+    current: usize,
+}
+
+impl AppIds {
+    fn parse(api_id: &str) -> Result<AppIds> {
+        let ids = api_id
+            .split(',')
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .collect::<Vec<String>>();
+        if ids.is_empty() {
+            return Err(anyhow::anyhow!("api_config has an empty api_id"));
+        }
+        let current = Utc::now().timestamp_subsec_nanos() as usize % ids.len();
+        Ok(AppIds { ids, current })
+    }
+
+    fn id(&self) -> &str {
+        &self.ids[self.current]
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        self.current
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub(crate) fn next(&mut self) {
+        self.current = (self.current + 1) % self.ids.len();
+    }
+}
+
+pub(crate) fn open_provider(api_config: &Dictionary) -> Result<Box<dyn ReadingProvider>> {
+    Ok(match provider_from(|key| api_config.get(key))? {
+        Provider::Goo(goo) => Box::new(goo),
+        Provider::Yahoo(yahoo) => Box::new(yahoo),
+    })
+}
+
+enum Provider {
+    Goo(Goo),
+    Yahoo(Yahoo),
+}
+
+// the provider for the settings config returns
+fn provider_from<F>(config: F) -> Result<Provider>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let config_host = |key: &str, default: &str| checked_host(key, config(key), default);
+    // without an application id there is nothing to send, the page is passed through
+    let app_ids = config("api_id")
+        .ok_or_else(|| anyhow::anyhow!("api_config has no api_id for the reading API"))?;
+    let app_ids = AppIds::parse(&app_ids)?;
+    let output_type = config("output_type").unwrap_or_else(|| DEFAULT_OUTPUT_TYPE.to_string());
+    if !OUTPUT_TYPES.contains(&output_type.as_str()) {
+        return Err(anyhow::anyhow!("unsupported output_type: {}", output_type));
+    }
+    match config("provider").as_deref() {
+        None | Some("goo") => {
+            let backend = config_host("api_backend", API_BACKEND);
+            let host = config_host("api_host", &backend);
+            Ok(Provider::Goo(Goo {
+                url: format!("https://{}/api/hiragana", host),
+                backend,
+                app_ids,
+                output_type,
+            }))
+        }
+        Some("yahoo") => {
+            let backend = config_host("api_backend", YAHOO_BACKEND);
+            let host = config_host("api_host", &backend);
+            Ok(Provider::Yahoo(Yahoo {
+                url: format!("https://{}/FuriganaService/V2/furigana", host),
+                backend,
+                app_ids,
+                output_type,
+            }))
+        }
+        Some(provider) => Err(anyhow::anyhow!("unknown provider: {}", provider)),
+    }
+}
+
+// https://labs.goo.ne.jp/api/jp/hiragana-translation/
+struct Goo {
+    backend: String,
+    url: String,
+    app_ids: AppIds,
+    output_type: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReadingReq {
+    app_id: String,
+    request_id: String,
+    sentence: String,
+    output_type: String,
+}
+
+impl ReadingProvider for Goo {
+    fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    fn request_body(&self, sentence: &str, request_id: &str) -> Result<String> {
+        let reading_req = ReadingReq {
+            app_id: self.app_ids.id().to_string(),
+            request_id: request_id.to_string(),
+            sentence: sentence.to_string(),
+            output_type: self.output_type.clone(),
+        };
+        Ok(serde_json::to_string(&reading_req)?)
+    }
+
+    fn request(&self, req_body: &str) -> Result<Request<Body>> {
+        Ok(Request::builder()
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "application/json")
+            .uri(self.url.as_str())
+            .body(Body::from(req_body))?)
+    }
+
+    fn parse(&self, resp_body: &str, _request_id: &str) -> Result<ReadingResp> {
+        let reading_resp: ReadingResp = serde_json::from_str(resp_body)?;
+        Ok(reading_resp)
+    }
+
+    fn app_ids(&self) -> &AppIds {
+        &self.app_ids
+    }
+
+    fn app_ids_mut(&mut self) -> &mut AppIds {
+        &mut self.app_ids
+    }
+}
+
+// https://developer.yahoo.co.jp/webapi/jlp/furigana/v2/furigana.html
+struct Yahoo {
+    backend: String,
+    url: String,
+    app_ids: AppIds,
+    output_type: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct YahooReq {
+    id: String,
+    jsonrpc: String,
+    method: String,
+    params: YahooParams,
+}
+
+#[derive(Serialize, Deserialize)]
+struct YahooParams {
+    q: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct YahooResp {
+    result: YahooResult,
+}
+
+#[derive(Serialize, Deserialize)]
+struct YahooResult {
+    word: Vec<YahooWord>,
+}
+
+// words written in kana come back without furigana, compounds with the words they are made of
+#[derive(Serialize, Deserialize)]
+struct YahooWord {
+    surface: String,
+    furigana: Option<String>,
+    roman: Option<String>,
+    subword: Option<Vec<YahooWord>>,
+}
+
+impl YahooWord {
+    // subwords are separated like words, kanji_ruby can place them over their kanji
+    fn kana(self) -> String {
+        match self.subword {
+            Some(subword) if !subword.is_empty() => subword
+                .into_iter()
+                .map(YahooWord::kana)
+                .collect::<Vec<String>>()
+                .join(" "),
+            _ => self.furigana.unwrap_or(self.surface),
+        }
+    }
+}
+
+impl ReadingProvider for Yahoo {
+    fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    fn request_body(&self, sentence: &str, request_id: &str) -> Result<String> {
+        let yahoo_req = YahooReq {
+            id: request_id.to_string(),
+            jsonrpc: "2.0".to_string(),
+            method: "jlp.furiganaservice.furigana".to_string(),
+            params: YahooParams {
+                q: sentence.to_string(),
+            },
+        };
+        Ok(serde_json::to_string(&yahoo_req)?)
+    }
+
+    fn request(&self, req_body: &str) -> Result<Request<Body>> {
+        Ok(Request::builder()
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::USER_AGENT,
+                format!("Yahoo AppID: {}", self.app_ids.id()),
+            )
+            .uri(self.url.as_str())
+            .body(Body::from(req_body))?)
+    }
+
+    fn parse(&self, resp_body: &str, request_id: &str) -> Result<ReadingResp> {
+        let yahoo_resp: YahooResp = serde_json::from_str(resp_body)?;
+        let mut words = Vec::new();
+        for word in yahoo_resp.result.word {
+            words.push(match self.output_type.as_str() {
+                "roman" => word.roman.unwrap_or(word.surface),
+                "katakana" => word.kana().chars().map(to_katakana).collect(),
+                _ => word.kana(),
+            });
+        }
+        Ok(ReadingResp {
+            converted: words.join(" "),
+            output_type: self.output_type.clone(),
+            request_id: request_id.to_string(),
+        })
+    }
+
+    fn app_ids(&self) -> &AppIds {
+        &self.app_ids
+    }
+
+    fn app_ids_mut(&mut self) -> &mut AppIds {
+        &mut self.app_ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAHOO_RESP: &str = r#"{"id":"r1","jsonrpc":"2.0","result":{"word":[
+        {"furigana":"かんじ","roman":"kanzi","surface":"漢字"},
+        {"surface":"と"},
+        {"furigana":"ひらがな","roman":"hiragana","surface":"平仮名"},
+        {"surface":"、"},
+        {"furigana":"とうきょうと","roman":"toukyouto","surface":"東京都","subword":[
+            {"furigana":"とうきょう","roman":"toukyou","surface":"東京"},
+            {"furigana":"と","roman":"to","surface":"都"}
+        ]}
+    ]}}"#;
+
+    fn yahoo(output_type: &str) -> Yahoo {
+        Yahoo {
+            backend: YAHOO_BACKEND.to_string(),
+            url: "https://jlp.yahooapis.jp/FuriganaService/V2/furigana".to_string(),
+            app_ids: AppIds::parse("id1").unwrap(),
+            output_type: output_type.to_string(),
+        }
+    }
+
+    fn provider(settings: &[(&str, &str)]) -> Result<Provider> {
+        provider_from(|key| {
+            settings
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn providers_need_an_api_id_and_default_to_hiragana() {
+        let e = provider(&[("output_type", "katakana")]).err().unwrap();
+        assert!(e.to_string().contains("no api_id"), "{}", e);
+        match provider(&[("api_id", "id1")]).unwrap() {
+            Provider::Goo(goo) => {
+                assert_eq!(goo.backend, API_BACKEND);
+                assert_eq!(goo.output_type, "hiragana");
+            }
+            Provider::Yahoo(_) => panic!("goo is the default provider"),
+        }
+        match provider(&[("api_id", "id1"), ("provider", "yahoo")]).unwrap() {
+            Provider::Yahoo(yahoo) => assert_eq!(yahoo.output_type, "hiragana"),
+            Provider::Goo(_) => panic!("yahoo was asked for"),
+        }
+        assert!(provider(&[("api_id", "id1"), ("output_type", "kanji")]).is_err());
+        assert!(provider(&[("api_id", "id1"), ("provider", "bing")]).is_err());
+    }
+
+    #[test]
+    fn app_ids_rotate_over_every_key() {
+        let mut app_ids = AppIds::parse("id1, id2,,id3").unwrap();
+        assert_eq!(app_ids.len(), 3);
+        let first = app_ids.index();
+        let mut used = Vec::new();
+        for _ in 0..3 {
+            used.push(app_ids.id().to_string());
+            app_ids.next();
+        }
+        used.sort();
+        assert_eq!(used, ["id1", "id2", "id3"]);
+        assert_eq!(app_ids.index(), first);
+        assert!(AppIds::parse(" , ").is_err());
+    }
+
+    #[test]
+    fn goo_answers_are_read_as_they_are() {
+        let goo = Goo {
+            backend: API_BACKEND.to_string(),
+            url: "https://labs.goo.ne.jp/api/hiragana".to_string(),
+            app_ids: AppIds::parse("id1").unwrap(),
+            output_type: "hiragana".to_string(),
+        };
+        let req_body: serde_json::Value =
+            serde_json::from_str(&goo.request_body("漢字", "r1").unwrap()).unwrap();
+        assert_eq!(req_body["app_id"], "id1");
+        assert_eq!(req_body["sentence"], "漢字");
+        assert_eq!(req_body["output_type"], "hiragana");
+        let reading_resp = goo
+            .parse(
+                r#"{"request_id":"r1","output_type":"hiragana","converted":"かんじ が よめる"}"#,
+                "r1",
+            )
+            .unwrap();
+        assert_eq!(reading_resp.converted, "かんじ が よめる");
+        assert_eq!(reading_resp.output_type, "hiragana");
+        assert!(goo.parse(r#"{"error":{"code":400}}"#, "r1").is_err());
+    }
+
+    #[test]
+    fn yahoo_words_are_joined_like_goo_readings() {
+        // words in kana and punctuation come back without furigana, compounds are
+        // read by their subwords
+        let reading_resp = yahoo("hiragana").parse(YAHOO_RESP, "r1").unwrap();
+        assert_eq!(
+            reading_resp.converted,
+            "かんじ と ひらがな 、 とうきょう と"
+        );
+        assert_eq!(reading_resp.request_id, "r1");
+        assert_eq!(
+            yahoo("katakana").parse(YAHOO_RESP, "r1").unwrap().converted,
+            "カンジ ト ヒラガナ 、 トウキョウ ト"
+        );
+        assert_eq!(
+            yahoo("roman").parse(YAHOO_RESP, "r1").unwrap().converted,
+            "kanzi と hiragana 、 toukyouto"
+        );
+        let req_body: serde_json::Value =
+            serde_json::from_str(&yahoo("hiragana").request_body("漢字", "r1").unwrap()).unwrap();
+        assert_eq!(req_body["params"]["q"], "漢字");
+        assert_eq!(req_body["method"], "jlp.furiganaservice.furigana");
+    }
+}
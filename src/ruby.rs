@@ -0,0 +1,815 @@
+use crate::html::{char_at, is_cjk_ideograph, HtmlPart};
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+// marks where the reading API started a new word in a kana reading
+pub const WORD_BREAK: char = '\u{200b}';
+// between the readings of the kanji of a compound with KanjiRuby::Spaced
+pub const THIN_SPACE: char = '\u{2009}';
+
+// how the reading of a kanji compound is placed where the API's word breaks give
+// each kanji a reading of its own, e.g. 日本語 read as に ほん ご
+#[derive(Clone, Copy, PartialEq)]
+pub enum KanjiRuby {
+    // one reading over the whole compound, にほんご
+    Compound,
+    // a ruby for every kanji
+    Split,
+    // one ruby with the readings of the kanji spaced apart, に ほん ご
+    Spaced,
+}
+// ruby-position is inherited, so data-ruby-position="under" or "over" on any element of
+// the page places the furigana of everything inside it, nested elements included
+pub const FURIGANA_STYLE: &str = "ruby{line-height:1}rt{font-size:0.5em;line-height:1}body{line-height:2;ruby-position:over}[data-ruby-position=under]{ruby-position:under}[data-ruby-position=over]{ruby-position:over}";
+
+pub const SPAN_STYLE: &str = ".furigana-base{display:inline-flex;flex-direction:var(--furigana-direction,column-reverse);align-items:center;line-height:1}.furigana-reading{font-size:0.5em;line-height:1}body{line-height:2}[data-ruby-position=under]{--furigana-direction:column}[data-ruby-position=over]{--furigana-direction:column-reverse}";
+
+// aria_hidden keeps screen readers from reading a word twice, once from the base and
+// once from the furigana
+pub fn ruby_template(rp_open: &str, rp_close: &str, aria_hidden: bool) -> String {
+    let hidden = if aria_hidden {
+        r#" aria-hidden="true""#
+    } else {
+        ""
+    };
+    format!(
+        "<ruby>{{base}}<rp{hidden}>{}</rp><rt{hidden}>{{reading}}</rt><rp{hidden}>{}</rp></ruby>",
+        rp_open,
+        rp_close,
+        hidden = hidden
+    )
+}
+
+// render_mode=span, for pages whose sanitizers strip <ruby>
+pub fn span_template(aria_hidden: bool) -> String {
+    let hidden = if aria_hidden {
+        r#" aria-hidden="true""#
+    } else {
+        ""
+    };
+    format!(
+        r#"<span class="furigana-base">{{base}}<span class="furigana-reading"{}>{{reading}}</span></span>"#,
+        hidden
+    )
+}
+
+pub fn inject_style(html: &str, furigana_style: &str, stylesheet_url: Option<String>) -> String {
+    insert_in_head(html, &style_markup(furigana_style, stylesheet_url))
+}
+
+pub fn style_markup(furigana_style: &str, stylesheet_url: Option<String>) -> String {
+    match stylesheet_url {
+        Some(url) => format!(r#"<link rel="stylesheet" href="{}">"#, url),
+        None => format!("<style>{}</style>", furigana_style),
+    }
+}
+
+pub fn insert_in_head(html: &str, markup: &str) -> String {
+    let pos = head_position(html);
+    format!("{}{}{}", &html[..pos], markup, &html[pos..])
+}
+
+// at the start of <head>, or of <body> when there is no head; a page with neither gets
+// it after <html> or the doctype, markup before the doctype puts browsers in quirks mode
+pub fn head_position(html: &str) -> usize {
+    head_or_body_position(html).unwrap_or_else(|| {
+        let lower = html.to_ascii_lowercase();
+        find_open_tag_end(&lower, "html")
+            .or_else(|| {
+                let start = lower.find("<!doctype")?;
+                lower[start..].find('>').map(|end| start + end + 1)
+            })
+            .unwrap_or(0)
+    })
+}
+
+// the start of <head>, or of <body> when there is no head; a page that has either
+// gets nothing before them
+pub fn head_or_body_position(html: &str) -> Option<usize> {
+    let lower = html.to_ascii_lowercase();
+    find_open_tag_end(&lower, "head").or_else(|| find_open_tag_end(&lower, "body"))
+}
+
+fn find_open_tag_end(lower_html: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    while let Some(found) = lower_html[from..].find(&open) {
+        let start = from + found;
+        let after = start + open.len();
+        match lower_html[after..].chars().next() {
+            Some(ch) if ch == '>' || ch.is_whitespace() => {
+                return lower_html[after..].find('>').map(|end| after + end + 1);
+            }
+            _ => from = after,
+        }
+    }
+    None
+}
+
+// get_readings returns a reading, or None, for every run; it is not called for a
+// page without runs
+pub fn generate_html_with_ruby<F>(
+    html: &str,
+    parts: &[HtmlPart],
+    jp_runs: &[String],
+    ruby_template: &str,
+    kanji_ruby: KanjiRuby,
+    romaji: bool,
+    get_readings: F,
+) -> Result<String>
+where
+    F: FnOnce(&[String]) -> Result<Vec<Option<String>>>,
+{
+    let mut html_page = String::with_capacity(html.len());
+    let ruby = if jp_runs.is_empty() {
+        Vec::new()
+    } else {
+        get_readings(jp_runs)?
+    };
+    let mut i = 0;
+    for part in parts {
+        let content = &html[part.start..part.end];
+        log::debug!("content: {}", content);
+        if part.need_ruby {
+            // the API may merge or drop runs, leave runs without a reading as they are
+            let reading = ruby.get(i).cloned().flatten();
+            i += 1;
+            let reading = match reading {
+                Some(reading) => reading,
+                None => {
+                    log::warn!("no reading returned for run: {}", content);
+                    html_page.push_str(content);
+                    continue;
+                }
+            };
+            if part.in_title {
+                let reading = reading.replace(WORD_BREAK, "");
+                write!(&mut html_page, "{}({})", content, escape_html(&reading))?;
+                continue;
+            }
+            let mut segments =
+                split_okurigana(content, &reading, kanji_ruby != KanjiRuby::Compound);
+            if kanji_ruby == KanjiRuby::Spaced {
+                segments = space_kanji_readings(segments);
+            }
+            for (base, rt) in segments {
+                match rt {
+                    // the reading comes from outside and must not be able to add markup,
+                    // the base is page source that only holds Japanese and character references
+                    Some(rt) => {
+                        let rt = if romaji {
+                            format!("{} ({})", rt, romanize(&rt))
+                        } else {
+                            rt
+                        };
+                        let ruby_html = ruby_template
+                            .replace("{base}", &base)
+                            .replace("{reading}", &escape_html(&rt));
+                        log::debug!("{}", ruby_html);
+                        html_page.push_str(&ruby_html);
+                    }
+                    None => write!(&mut html_page, "{}", base)?,
+                }
+            }
+        } else {
+            html_page.push_str(content);
+        }
+    }
+
+    Ok(html_page)
+}
+
+// the kanji split_okurigana gave a reading each are put back together, as only a
+// kanji segment can be split there is no kana between them
+fn space_kanji_readings(segments: Vec<(String, Option<String>)>) -> Vec<(String, Option<String>)> {
+    let mut spaced: Vec<(String, Option<String>)> = Vec::with_capacity(segments.len());
+    for (base, rt) in segments {
+        if let (Some(rt), Some((last_base, Some(last_rt)))) = (&rt, spaced.last_mut()) {
+            last_base.push_str(&base);
+            last_rt.push(THIN_SPACE);
+            last_rt.push_str(rt);
+            continue;
+        }
+        spaced.push((base, rt));
+    }
+    spaced
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// each char of the segment as it is written in the page, character references included
+struct RunSegment {
+    raws: Vec<String>,
+    text: Vec<char>,
+    kanji: bool,
+}
+
+// Split a run into kanji and kana segments and place the reading over the kanji only,
+// e.g. お茶漬け/おちゃづけ -> お, 茶漬(ちゃづ), け. Falls back to the whole run when the
+// kana in the run can not be found in the reading. With kanji_ruby, a kanji segment
+// is split further where the API's word breaks give each kanji a word of its own.
+pub fn split_okurigana(
+    run: &str,
+    reading: &str,
+    kanji_ruby: bool,
+) -> Vec<(String, Option<String>)> {
+    let run_chars = run.chars().collect::<Vec<char>>();
+    let mut segments: Vec<RunSegment> = Vec::new();
+    let mut i = 0;
+    while i < run_chars.len() {
+        let (ch, ch_len) = char_at(&run_chars, i);
+        let kanji = is_cjk_ideograph(&ch);
+        let raw = run_chars[i..i + ch_len].iter().collect::<String>();
+        match segments.last_mut() {
+            Some(segment) if segment.kanji == kanji => {
+                segment.raws.push(raw);
+                segment.text.push(ch);
+            }
+            _ => segments.push(RunSegment {
+                raws: vec![raw],
+                text: vec![ch],
+                kanji,
+            }),
+        }
+        i += ch_len;
+    }
+
+    // word breaks are kept as the positions in the reading where a word starts
+    let mut breaks = HashSet::new();
+    let mut reading_chars = Vec::new();
+    for ch in reading.chars() {
+        if ch == WORD_BREAK {
+            breaks.insert(reading_chars.len());
+        } else {
+            reading_chars.push(ch);
+        }
+    }
+    let mut ends = Vec::new();
+    let mut failed = HashSet::new();
+    if !segments.iter().any(|segment| segment.kanji)
+        || !align_reading(&segments, 0, &reading_chars, 0, &mut ends, &mut failed)
+    {
+        return vec![(run.to_string(), Some(reading_chars.into_iter().collect()))];
+    }
+
+    let mut start = 0;
+    let mut aligned = Vec::new();
+    for (segment, end) in segments.into_iter().zip(ends) {
+        if !segment.kanji {
+            aligned.push((segment.raws.concat(), None));
+            start = end;
+            continue;
+        }
+        // without the API's surfaces only one word per kanji can be placed for sure,
+        // jukujigo like 今日 and longer words keep the reading over the whole segment
+        let mut cuts = (start + 1..end)
+            .filter(|pos| breaks.contains(pos))
+            .collect::<Vec<usize>>();
+        if kanji_ruby && cuts.len() + 1 == segment.raws.len() {
+            cuts.push(end);
+            for (raw, cut) in segment.raws.into_iter().zip(cuts) {
+                let rt = reading_chars[start..cut].iter().collect::<String>();
+                aligned.push((raw, Some(rt)));
+                start = cut;
+            }
+        } else {
+            let rt = reading_chars[start..end].iter().collect::<String>();
+            aligned.push((segment.raws.concat(), Some(rt)));
+            start = end;
+        }
+    }
+    aligned
+}
+
+// Split the reading of runs read as one text back at the run boundaries, e.g.
+// 食, べ物 and たべもの -> た, べもの. None when a boundary falls inside a word whose
+// reading can not be divided, e.g. 東, 京 and とうきょう.
+pub fn split_reading(runs: &[String], reading: &str) -> Option<Vec<String>> {
+    let reading_chars = reading
+        .chars()
+        .filter(|ch| *ch != WORD_BREAK)
+        .collect::<Vec<char>>();
+    let mut boundaries = Vec::new();
+    let mut run_end = 0;
+    for run in runs {
+        run_end += run.chars().count();
+        boundaries.push(run_end);
+    }
+    // kana in the text is read one to one, kanji only as a whole segment or, where
+    // the API's word breaks allow it, one by one
+    let mut cuts = Vec::new();
+    let mut text_pos = 0;
+    let mut reading_pos = 0;
+    let mut boundaries = boundaries.into_iter().peekable();
+    for (base, rt) in split_okurigana(&runs.concat(), reading, true) {
+        let len = base.chars().count();
+        let rt_len = rt.as_ref().map_or(len, |rt| rt.chars().count());
+        while let Some(&boundary) = boundaries.peek() {
+            if boundary > text_pos + len {
+                break;
+            }
+            if boundary == text_pos + len {
+                cuts.push(reading_pos + rt_len);
+            } else if rt.is_none() {
+                cuts.push(reading_pos + boundary - text_pos);
+            } else {
+                return None;
+            }
+            boundaries.next();
+        }
+        text_pos += len;
+        reading_pos += rt_len;
+    }
+    let mut start = 0;
+    let mut split = Vec::with_capacity(cuts.len());
+    for cut in cuts {
+        split.push(reading_chars[start..cut].iter().collect::<String>());
+        start = cut;
+    }
+    Some(split)
+}
+
+fn align_reading(
+    segments: &[RunSegment],
+    index: usize,
+    reading: &[char],
+    pos: usize,
+    ends: &mut Vec<usize>,
+    failed: &mut HashSet<(usize, usize)>,
+) -> bool {
+    if index == segments.len() {
+        return pos == reading.len();
+    }
+    if failed.contains(&(index, pos)) {
+        return false;
+    }
+    let segment = &segments[index];
+    let candidates = if segment.kanji {
+        (pos + 1..=reading.len()).collect::<Vec<usize>>()
+    } else {
+        let end = pos + segment.text.len();
+        let matches = end <= reading.len()
+            && segment
+                .text
+                .iter()
+                .zip(reading[pos..end].iter())
+                .all(|(a, b)| to_hiragana(*a) == to_hiragana(*b));
+        if matches {
+            vec![end]
+        } else {
+            vec![]
+        }
+    };
+    for end in candidates {
+        ends.push(end);
+        if align_reading(segments, index + 1, reading, end, ends, failed) {
+            return true;
+        }
+        ends.pop();
+    }
+    failed.insert((index, pos));
+    false
+}
+
+pub fn to_hiragana(ch: char) -> char {
+    match ch {
+        '\u{30a1}'..='\u{30f6}' => std::char::from_u32(ch as u32 - 0x60).unwrap_or(ch),
+        _ => ch,
+    }
+}
+
+pub fn to_katakana(ch: char) -> char {
+    match ch {
+        '\u{3041}'..='\u{3096}' => std::char::from_u32(ch as u32 + 0x60).unwrap_or(ch),
+        _ => ch,
+    }
+}
+
+// Hepburn romaji of a kana reading, with macrons for long o and u and for ー,
+// e.g. とうきょう -> tōkyō, しんぶん -> shinbun, きんえん -> kin'en
+pub fn romanize(kana: &str) -> String {
+    let chars = kana.chars().map(to_hiragana).collect::<Vec<char>>();
+    let mut romaji = String::new();
+    let mut prev = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        i += 1;
+        let mut syllable = match kana_romaji(ch) {
+            Some(syllable) => syllable.to_string(),
+            None => {
+                if ch == 'ー' {
+                    lengthen_last_vowel(&mut romaji);
+                } else if ch != 'っ' {
+                    romaji.push(ch);
+                }
+                prev = Some(ch);
+                continue;
+            }
+        };
+        match chars.get(i) {
+            // きゃ -> kya, しゃ -> sha, じゃ -> ja
+            Some(&small) if syllable.len() > 1 && syllable.ends_with('i') && is_small_y(small) => {
+                i += 1;
+                let stem = &syllable[..syllable.len() - 1];
+                let vowel = &kana_romaji(small).unwrap_or("ya")[1..];
+                syllable = if stem.ends_with("sh") || stem.ends_with("ch") || stem.ends_with('j') {
+                    format!("{}{}", stem, vowel)
+                } else {
+                    format!("{}y{}", stem, vowel)
+                };
+            }
+            // ふぁ -> fa, てぃ -> ti
+            Some(&small) if syllable.len() > 1 && is_small_vowel(small) => {
+                i += 1;
+                syllable.pop();
+                syllable.push_str(kana_romaji(small).unwrap_or(""));
+            }
+            _ => {}
+        }
+        match prev {
+            // っ doubles the consonant that follows, っち -> tchi
+            Some('っ') if syllable.starts_with("ch") => romaji.push('t'),
+            Some('っ') => {
+                if let Some(consonant) = syllable.chars().next().filter(|ch| !is_vowel(*ch)) {
+                    romaji.push(consonant);
+                }
+            }
+            // ん before a vowel or y is written n' so it can not be read as な or にゃ
+            Some('ん') if syllable.starts_with(|first: char| is_vowel(first) || first == 'y') => {
+                romaji.push('\'')
+            }
+            // おう, おお and うう are one long vowel
+            Some(_)
+                if (syllable == "u" && romaji.ends_with(|last| last == 'o' || last == 'u'))
+                    || (syllable == "o" && romaji.ends_with('o')) =>
+            {
+                lengthen_last_vowel(&mut romaji);
+                prev = Some(ch);
+                continue;
+            }
+            _ => {}
+        }
+        romaji.push_str(&syllable);
+        prev = Some(ch);
+    }
+    romaji
+}
+
+fn lengthen_last_vowel(romaji: &mut String) {
+    let long = match romaji.chars().last() {
+        Some('a') => 'ā',
+        Some('i') => 'ī',
+        Some('u') => 'ū',
+        Some('e') => 'ē',
+        Some('o') => 'ō',
+        _ => return,
+    };
+    romaji.pop();
+    romaji.push(long);
+}
+
+fn is_vowel(ch: char) -> bool {
+    "aiueo".contains(ch)
+}
+
+fn is_small_y(ch: char) -> bool {
+    ch == 'ゃ' || ch == 'ゅ' || ch == 'ょ'
+}
+
+fn is_small_vowel(ch: char) -> bool {
+    ch == 'ぁ' || ch == 'ぃ' || ch == 'ぅ' || ch == 'ぇ' || ch == 'ぉ'
+}
+
+fn kana_romaji(ch: char) -> Option<&'static str> {
+    let romaji = match ch {
+        'あ' | 'ぁ' => "a",
+        'い' | 'ぃ' => "i",
+        'う' | 'ぅ' => "u",
+        'え' | 'ぇ' => "e",
+        'お' | 'ぉ' => "o",
+        'か' => "ka",
+        'き' => "ki",
+        'く' => "ku",
+        'け' => "ke",
+        'こ' => "ko",
+        'が' => "ga",
+        'ぎ' => "gi",
+        'ぐ' => "gu",
+        'げ' => "ge",
+        'ご' => "go",
+        'さ' => "sa",
+        'し' => "shi",
+        'す' => "su",
+        'せ' => "se",
+        'そ' => "so",
+        'ざ' => "za",
+        'じ' | 'ぢ' => "ji",
+        'ず' | 'づ' => "zu",
+        'ぜ' => "ze",
+        'ぞ' => "zo",
+        'た' => "ta",
+        'ち' => "chi",
+        'つ' => "tsu",
+        'て' => "te",
+        'と' => "to",
+        'だ' => "da",
+        'で' => "de",
+        'ど' => "do",
+        'な' => "na",
+        'に' => "ni",
+        'ぬ' => "nu",
+        'ね' => "ne",
+        'の' => "no",
+        'は' => "ha",
+        'ひ' => "hi",
+        'ふ' => "fu",
+        'へ' => "he",
+        'ほ' => "ho",
+        'ば' => "ba",
+        'び' => "bi",
+        'ぶ' => "bu",
+        'べ' => "be",
+        'ぼ' => "bo",
+        'ぱ' => "pa",
+        'ぴ' => "pi",
+        'ぷ' => "pu",
+        'ぺ' => "pe",
+        'ぽ' => "po",
+        'ま' => "ma",
+        'み' => "mi",
+        'む' => "mu",
+        'め' => "me",
+        'も' => "mo",
+        'や' | 'ゃ' => "ya",
+        'ゆ' | 'ゅ' => "yu",
+        'よ' | 'ょ' => "yo",
+        'ら' => "ra",
+        'り' => "ri",
+        'る' => "ru",
+        'れ' => "re",
+        'ろ' => "ro",
+        'わ' => "wa",
+        'ゐ' => "i",
+        'ゑ' => "e",
+        'を' => "o",
+        'ん' => "n",
+        'ゔ' => "vu",
+        _ => return None,
+    };
+    Some(romaji)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_in_head_after_head_or_body() {
+        assert_eq!(
+            insert_in_head(
+                "<html><head lang=\"ja\"><title>t</title></head></html>",
+                "<x>"
+            ),
+            "<html><head lang=\"ja\"><x><title>t</title></head></html>"
+        );
+        assert_eq!(
+            insert_in_head("<header></header><body><p>本</p></body>", "<x>"),
+            "<header></header><body><x><p>本</p></body>"
+        );
+    }
+
+    #[test]
+    fn insert_in_head_after_html_or_doctype() {
+        assert_eq!(
+            insert_in_head("<!DOCTYPE html><html lang=\"ja\"><p>本</p></html>", "<x>"),
+            "<!DOCTYPE html><html lang=\"ja\"><x><p>本</p></html>"
+        );
+        assert_eq!(
+            insert_in_head("<!DOCTYPE html>\n<p>本</p>", "<x>"),
+            "<!DOCTYPE html><x>\n<p>本</p>"
+        );
+        assert_eq!(insert_in_head("<p>本</p>", "<x>"), "<x><p>本</p>");
+    }
+
+    fn segments(segments: &[(&str, Option<&str>)]) -> Vec<(String, Option<String>)> {
+        segments
+            .iter()
+            .map(|(base, rt)| (base.to_string(), rt.map(|rt| rt.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn split_okurigana_places_the_reading_over_the_kanji() {
+        assert_eq!(
+            split_okurigana("お茶漬け", "おちゃづけ", false),
+            segments(&[("お", None), ("茶漬", Some("ちゃづ")), ("け", None)])
+        );
+        // katakana in the run matches the hiragana of the reading
+        assert_eq!(
+            split_okurigana("パン屋", "ぱんや", false),
+            segments(&[("パン", None), ("屋", Some("や"))])
+        );
+        // the kana can not be found in the reading
+        assert_eq!(
+            split_okurigana("食べる", "たべた", false),
+            segments(&[("食べる", Some("たべた"))])
+        );
+    }
+
+    #[test]
+    fn split_okurigana_splits_kanji_at_word_breaks() {
+        let reading = "に\u{200b}ほん\u{200b}ご";
+        assert_eq!(
+            split_okurigana("日本語", reading, true),
+            segments(&[("日", Some("に")), ("本", Some("ほん")), ("語", Some("ご"))])
+        );
+        assert_eq!(
+            split_okurigana("日本語", reading, false),
+            segments(&[("日本語", Some("にほんご"))])
+        );
+        // a word break for every kanji is needed, jukujigo keep one reading
+        assert_eq!(
+            split_okurigana("今日", "きょう", true),
+            segments(&[("今日", Some("きょう"))])
+        );
+    }
+
+    #[test]
+    fn spaced_readings_keep_the_compound_in_one_ruby() {
+        let spaced =
+            |run: &str, reading: &str| space_kanji_readings(split_okurigana(run, reading, true));
+        assert_eq!(
+            spaced("日本", "に\u{200b}ほん"),
+            segments(&[("日本", Some("に\u{2009}ほん"))])
+        );
+        assert_eq!(
+            spaced("日本語", "に\u{200b}ほん\u{200b}ご"),
+            segments(&[("日本語", Some("に\u{2009}ほん\u{2009}ご"))])
+        );
+        // kana between two compounds starts a new ruby
+        assert_eq!(
+            spaced(
+                "東京の大阪",
+                "とう\u{200b}きょう\u{200b}の\u{200b}おお\u{200b}さか"
+            ),
+            segments(&[
+                ("東京", Some("とう\u{2009}きょう")),
+                ("の", None),
+                ("大阪", Some("おお\u{2009}さか")),
+            ])
+        );
+    }
+
+    #[test]
+    fn split_reading_at_run_boundaries() {
+        let runs = |runs: &[&str]| {
+            runs.iter()
+                .map(|run| run.to_string())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            split_reading(&runs(&["食", "べ物"]), "たべもの"),
+            Some(vec!["た".to_string(), "べもの".to_string()])
+        );
+        assert_eq!(
+            split_reading(&runs(&["日", "本"]), "に\u{200b}ほん"),
+            Some(vec!["に".to_string(), "ほん".to_string()])
+        );
+        assert_eq!(split_reading(&runs(&["東", "京"]), "とうきょう"), None);
+    }
+
+    #[test]
+    fn romanize_as_hepburn() {
+        assert_eq!(romanize("とうきょう"), "tōkyō");
+        assert_eq!(romanize("おおさか"), "ōsaka");
+        assert_eq!(romanize("しんぶん"), "shinbun");
+        assert_eq!(romanize("きんえん"), "kin'en");
+        assert_eq!(romanize("がっこう"), "gakkō");
+        assert_eq!(romanize("まっちゃ"), "matcha");
+        assert_eq!(romanize("じゃま"), "jama");
+        assert_eq!(romanize("コーヒー"), "kōhī");
+        assert_eq!(romanize("ファイル"), "fairu");
+    }
+
+    fn generate(html: &str, template: &str, reading: &str) -> String {
+        generate_with(html, template, KanjiRuby::Compound, reading)
+    }
+
+    fn generate_with(html: &str, template: &str, kanji_ruby: KanjiRuby, reading: &str) -> String {
+        let (parts, jp_runs) = crate::html::analyze_jp(html, false, &[]);
+        generate_html_with_ruby(
+            html,
+            &parts,
+            &jp_runs,
+            template,
+            kanji_ruby,
+            false,
+            |jp_runs| Ok(jp_runs.iter().map(|_| Some(reading.to_string())).collect()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn runs_past_the_readings_are_left_as_they_are() {
+        let html = "<p>東京</p><p>大阪</p><p>京都</p>";
+        let (parts, jp_runs) = crate::html::analyze_jp(html, false, &[]);
+        let page = generate_html_with_ruby(
+            html,
+            &parts,
+            &jp_runs,
+            &ruby_template("(", ")", false),
+            KanjiRuby::Compound,
+            false,
+            |_| Ok(vec![Some("とうきょう".to_string()), None]),
+        )
+        .unwrap();
+        assert_eq!(
+            page,
+            "<p><ruby>東京<rp>(</rp><rt>とうきょう</rt><rp>)</rp></ruby></p><p>大阪</p><p>京都</p>"
+        );
+    }
+
+    #[test]
+    fn kanji_ruby_places_the_readings_of_a_compound() {
+        let template = ruby_template("(", ")", false);
+        let reading = "に\u{200b}ほん\u{200b}ご\u{200b}の";
+        let generated =
+            |kanji_ruby| generate_with("<p>日本語の</p>", &template, kanji_ruby, reading);
+        assert_eq!(
+            generated(KanjiRuby::Compound),
+            "<p><ruby>日本語<rp>(</rp><rt>にほんご</rt><rp>)</rp></ruby>の</p>"
+        );
+        assert_eq!(
+            generated(KanjiRuby::Split),
+            "<p><ruby>日<rp>(</rp><rt>に</rt><rp>)</rp></ruby><ruby>本<rp>(</rp><rt>ほん</rt><rp>)</rp></ruby><ruby>語<rp>(</rp><rt>ご</rt><rp>)</rp></ruby>の</p>"
+        );
+        assert_eq!(
+            generated(KanjiRuby::Spaced),
+            "<p><ruby>日本語<rp>(</rp><rt>に\u{2009}ほん\u{2009}ご</rt><rp>)</rp></ruby>の</p>"
+        );
+    }
+
+    #[test]
+    fn ruby_position_is_left_to_the_page_and_the_styles() {
+        let html = r#"<div data-ruby-position="under"><p>漢字</p><p data-ruby-position="over">本</p></div>"#;
+        // the ruby inherits the position from the element, it gets no style of its own
+        assert_eq!(
+            generate(html, &ruby_template("(", ")", false), "よみ"),
+            r#"<div data-ruby-position="under"><p><ruby>漢字<rp>(</rp><rt>よみ</rt><rp>)</rp></ruby></p><p data-ruby-position="over"><ruby>本<rp>(</rp><rt>よみ</rt><rp>)</rp></ruby></p></div>"#
+        );
+        assert!(FURIGANA_STYLE.contains("[data-ruby-position=under]{ruby-position:under}"));
+        assert!(FURIGANA_STYLE.contains("[data-ruby-position=over]{ruby-position:over}"));
+        assert!(SPAN_STYLE.contains("[data-ruby-position=under]{--furigana-direction:column}"));
+        assert!(
+            SPAN_STYLE.contains("[data-ruby-position=over]{--furigana-direction:column-reverse}")
+        );
+    }
+
+    #[test]
+    fn aria_hidden_hides_only_the_reading() {
+        assert_eq!(
+            generate("<p>漢字</p>", &ruby_template("(", ")", true), "かんじ"),
+            r#"<p><ruby>漢字<rp aria-hidden="true">(</rp><rt aria-hidden="true">かんじ</rt><rp aria-hidden="true">)</rp></ruby></p>"#
+        );
+        assert_eq!(
+            generate("<p>漢字</p>", &span_template(true), "かんじ"),
+            r#"<p><span class="furigana-base">漢字<span class="furigana-reading" aria-hidden="true">かんじ</span></span></p>"#
+        );
+    }
+
+    #[test]
+    fn readings_with_markup_are_written_as_text() {
+        let reading = "<script>alert('&')</script>";
+        let escaped = "&lt;script&gt;alert(&#39;&amp;&#39;)&lt;/script&gt;";
+        assert_eq!(
+            generate("<p>漢字</p>", &ruby_template("(", ")", false), reading),
+            format!(
+                "<p><ruby>漢字<rp>(</rp><rt>{}</rt><rp>)</rp></ruby></p>",
+                escaped
+            )
+        );
+        assert_eq!(
+            generate("<p>漢字</p>", &span_template(false), reading),
+            format!(
+                r#"<p><span class="furigana-base">漢字<span class="furigana-reading">{}</span></span></p>"#,
+                escaped
+            )
+        );
+        assert_eq!(
+            generate(
+                "<title>漢字</title>",
+                &ruby_template("(", ")", false),
+                reading
+            ),
+            format!("<title>漢字({})</title>", escaped)
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use crate::html::VOID_TAGS;
+use std::ops::Range;
+
+// a compound selector of a tag name, an id and classes, e.g. article.main or #content
+struct Selector {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+}
+
+struct OpenTag {
+    name: String,
+    attrs: Vec<(String, String)>,
+    end: usize,
+}
+
+// Byte ranges of html taken by the elements that match one of the comma separated
+// selectors, from the start of the open tag to the end of the close tag. Elements
+// inside a matching element are part of its range.
+pub fn scope_ranges(html: &str, selectors: &str) -> Vec<Range<usize>> {
+    let selectors = selectors
+        .split(',')
+        .filter_map(|selector| {
+            let parsed = parse_selector(selector.trim());
+            if parsed.is_none() {
+                log::error!("unsupported scope_selector {:?}", selector);
+            }
+            parsed
+        })
+        .collect::<Vec<Selector>>();
+    let lower = html.to_ascii_lowercase();
+    let mut ranges = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = html[pos..].find('<') {
+        let start = pos + found;
+        if html[start..].starts_with("<!--") {
+            pos = html[start..]
+                .find("-->")
+                .map(|end| start + end + 3)
+                .unwrap_or_else(|| html.len());
+            continue;
+        }
+        let tag = match open_tag(html, start) {
+            Some(tag) => tag,
+            None => {
+                pos = start + 1;
+                continue;
+            }
+        };
+        if selectors.iter().any(|selector| selector.matches(&tag)) {
+            let end = element_end(&lower, &tag);
+            ranges.push(start..end);
+            pos = end;
+        } else {
+            pos = tag.end;
+        }
+    }
+    ranges
+}
+
+fn parse_selector(selector: &str) -> Option<Selector> {
+    let mut parsed = Selector {
+        tag: None,
+        id: None,
+        classes: Vec::new(),
+    };
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    for (i, ch) in selector.char_indices() {
+        if (ch == '.' || ch == '#') && i > start {
+            tokens.push(&selector[start..i]);
+            start = i;
+        }
+    }
+    tokens.push(&selector[start..]);
+    for token in tokens {
+        let name = token.trim_start_matches(|ch| ch == '.' || ch == '#');
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+        {
+            return None;
+        }
+        if token.starts_with('.') {
+            parsed.classes.push(name.to_string());
+        } else if token.starts_with('#') {
+            parsed.id = Some(name.to_string());
+        } else {
+            parsed.tag = Some(name.to_ascii_lowercase());
+        }
+    }
+    Some(parsed)
+}
+
+impl Selector {
+    fn matches(&self, tag: &OpenTag) -> bool {
+        let attr = |name: &str| {
+            tag.attrs
+                .iter()
+                .find(|(attr, _)| attr == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let classes = attr("class")
+            .unwrap_or("")
+            .split_whitespace()
+            .collect::<Vec<&str>>();
+        self.tag.as_ref().map_or(true, |name| *name == tag.name)
+            && self.id.as_deref().map_or(true, |id| attr("id") == Some(id))
+            && self
+                .classes
+                .iter()
+                .all(|class| classes.contains(&class.as_str()))
+    }
+}
+
+// the tag name and attributes of the open tag at start, up to the '>' outside quotes
+fn open_tag(html: &str, start: usize) -> Option<OpenTag> {
+    let bytes = html.as_bytes();
+    let is_delimiter = |b: u8| b.is_ascii_whitespace() || b == b'>' || b == b'/' || b == b'=';
+    let mut i = start + 1;
+    if i >= bytes.len() || !bytes[i].is_ascii_alphabetic() {
+        return None;
+    }
+    while i < bytes.len() && !is_delimiter(bytes[i]) {
+        i += 1;
+    }
+    let name = html[start + 1..i].to_ascii_lowercase();
+    let mut attrs = Vec::new();
+    loop {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        if i >= bytes.len() {
+            return Some(OpenTag {
+                name,
+                attrs,
+                end: bytes.len(),
+            });
+        }
+        if bytes[i] == b'>' {
+            return Some(OpenTag {
+                name,
+                attrs,
+                end: i + 1,
+            });
+        }
+        let name_start = i;
+        while i < bytes.len() && !is_delimiter(bytes[i]) {
+            i += 1;
+        }
+        let attr = html[name_start..i].to_ascii_lowercase();
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let mut value = "";
+        if i < bytes.len() && bytes[i] == b'=' {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if i < bytes.len() && (bytes[i] == b'"' || bytes[i] == b'\'') {
+                let quote = bytes[i];
+                let value_start = i + 1;
+                i = value_start;
+                while i < bytes.len() && bytes[i] != quote {
+                    i += 1;
+                }
+                value = &html[value_start..i];
+                i = (i + 1).min(bytes.len());
+            } else {
+                let value_start = i;
+                while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                    i += 1;
+                }
+                value = &html[value_start..i];
+            }
+        }
+        attrs.push((attr, value.to_string()));
+    }
+}
+
+// the end of the close tag that matches tag, counting nested elements of the same name;
+// an element that is never closed runs to the end of the page
+fn element_end(lower_html: &str, tag: &OpenTag) -> usize {
+    if VOID_TAGS.contains(&tag.name.as_str()) || lower_html[..tag.end].ends_with("/>") {
+        return tag.end;
+    }
+    let open = format!("<{}", tag.name);
+    let close = format!("</{}", tag.name);
+    let bytes = lower_html.as_bytes();
+    let ends_name = |pos: usize| {
+        pos >= bytes.len()
+            || bytes[pos].is_ascii_whitespace()
+            || bytes[pos] == b'>'
+            || bytes[pos] == b'/'
+    };
+    let mut depth = 1;
+    let mut pos = tag.end;
+    while let Some(found) = lower_html[pos..].find('<') {
+        let start = pos + found;
+        if lower_html[start..].starts_with(&close) && ends_name(start + close.len()) {
+            depth -= 1;
+            let end = lower_html[start..]
+                .find('>')
+                .map(|end| start + end + 1)
+                .unwrap_or_else(|| lower_html.len());
+            if depth == 0 {
+                return end;
+            }
+            pos = end;
+        } else {
+            if lower_html[start..].starts_with(&open) && ends_name(start + open.len()) {
+                depth += 1;
+            }
+            pos = start + 1;
+        }
+    }
+    lower_html.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = "<div><article class=\"main post\">本<article>文</article></article><p id=\"c\">京<img class=\"x\"></p><!-- <p id=\"c\"> --></div>";
+
+    fn scopes<'a>(html: &'a str, selectors: &str) -> Vec<&'a str> {
+        scope_ranges(html, selectors)
+            .into_iter()
+            .map(|range| &html[range])
+            .collect()
+    }
+
+    #[test]
+    fn scope_ranges_of_matching_elements() {
+        assert_eq!(
+            scopes(PAGE, "article.main"),
+            vec!["<article class=\"main post\">本<article>文</article></article>"]
+        );
+        assert_eq!(
+            scopes(PAGE, "#c, .post"),
+            vec![
+                "<article class=\"main post\">本<article>文</article></article>",
+                "<p id=\"c\">京<img class=\"x\"></p>",
+            ]
+        );
+        assert_eq!(scopes(PAGE, "P#C"), Vec::<&str>::new());
+        assert_eq!(scopes(PAGE, "p#c.x"), Vec::<&str>::new());
+        assert_eq!(scopes(PAGE, ".x"), vec!["<img class=\"x\">"]);
+    }
+
+    #[test]
+    fn scope_ranges_skip_unsupported_selectors() {
+        assert_eq!(scopes(PAGE, "div > p"), Vec::<&str>::new());
+        assert_eq!(
+            scopes(PAGE, "div > p, #c"),
+            vec!["<p id=\"c\">京<img class=\"x\"></p>"]
+        );
+    }
+}